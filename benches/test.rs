@@ -0,0 +1,49 @@
+use criterion::{BatchSize, BenchmarkId, Criterion, criterion_group, criterion_main};
+
+use loudgain_rust::args::SampleRateStrategy;
+use loudgain_rust::decode_audio::DecodedFile;
+use loudgain_rust::replaygain_scanner::{MEASURABLE_RATES, scan_file, ScanOptions};
+
+const SECONDS: u32 = 5;
+const CHANNELS: u32 = 2;
+// telephony, CD, high resolution and DXD rates, the first and last are outside of the measurable range
+const RATES: [u32; 4] = [8_000, 44_100, 96_000, 352_800];
+
+fn sine(rate: u32) -> Vec<i16> {
+    (0..rate * SECONDS)
+        .flat_map(|frame| {
+            let sample = ((frame as f64 * 2.0 * std::f64::consts::PI * 997.0 / rate as f64).sin() * 16384.0) as i16;
+            std::iter::repeat_n(sample, CHANNELS as usize)
+        })
+        .collect()
+}
+
+fn scan_strategies(c: &mut Criterion) {
+    let mut group = c.benchmark_group("scan_file");
+    group.sample_size(10);
+
+    for rate in RATES {
+        let pcm = sine(rate);
+        // outside of the measurable range Reject fails right away, which leaves nothing to measure
+        let strategies = if MEASURABLE_RATES.contains(&rate) {
+            vec![SampleRateStrategy::Native, SampleRateStrategy::Reject, SampleRateStrategy::Resample]
+        } else {
+            vec![SampleRateStrategy::Native, SampleRateStrategy::Resample]
+        };
+        for strategy in strategies {
+            let options = ScanOptions { rate_strategy: strategy.clone(), ..Default::default() };
+            group.bench_with_input(BenchmarkId::new(strategy.to_string(), rate), &pcm, |b, pcm| {
+                b.iter_batched(
+                    || DecodedFile::new(pcm.clone(), CHANNELS, rate),
+                    |file| scan_file(file, &options),
+                    BatchSize::LargeInput,
+                )
+            });
+        }
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, scan_strategies);
+criterion_main!(benches);
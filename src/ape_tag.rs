@@ -0,0 +1,254 @@
+use std::ops::Range;
+
+const PREAMBLE: &[u8; 8] = b"APETAGEX";
+const VERSION: u32 = 2000;
+const HEADER_SIZE: usize = 32;
+const ID3V1_SIZE: usize = 128;
+const HAS_HEADER: u32 = 1 << 31;
+const IS_HEADER: u32 = 1 << 29;
+// the end of a file that tells how long the trailing tags are
+pub(crate) const TRAILER_SIZE: usize = HEADER_SIZE + ID3V1_SIZE;
+
+/// An APEv2 tag, which mp3gain appends to MP3 files to keep its undo information.
+#[derive(Debug, Default, Clone)]
+pub struct ApeTag {
+    items: Vec<ApeItem>,
+}
+
+#[derive(Debug, Clone)]
+struct ApeItem {
+    key: String,
+    flags: u32,
+    value: Vec<u8>,
+}
+
+impl ApeTag {
+    /// Reads the tag at the end of the file, or right before its ID3v1 tag, along with where it is stored.
+    pub fn read(data: &[u8]) -> Option<(ApeTag, Range<usize>)> {
+        let end = id3v1_start(data);
+        let footer = data.get(end.checked_sub(HEADER_SIZE)?..end)?;
+        if &footer[0..8] != PREAMBLE {
+            return None;
+        }
+        let size = read_u32(footer, 12) as usize;
+        let count = read_u32(footer, 16);
+        let flags = read_u32(footer, 20);
+        let items_start = end.checked_sub(size)?;
+        let start = if flags & HAS_HEADER != 0 { items_start.checked_sub(HEADER_SIZE)? } else { items_start };
+
+        let mut items = Vec::with_capacity(count as usize);
+        let mut pos = items_start;
+        for _ in 0..count {
+            let header = data.get(pos..pos + 8)?;
+            let length = read_u32(header, 0) as usize;
+            let flags = read_u32(header, 4);
+            let key_length = data.get(pos + 8..end)?.iter().position(|byte| *byte == 0)?;
+            let key = String::from_utf8_lossy(&data[pos + 8..pos + 8 + key_length]).to_string();
+            let value_start = pos + 8 + key_length + 1;
+            let value = data.get(value_start..value_start + length)?.to_vec();
+            items.push(ApeItem { key, flags, value });
+            pos = value_start + length;
+        }
+
+        Some((ApeTag { items }, start..end))
+    }
+
+    /// Keys are case insensitive.
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.items.iter()
+            .find(|item| item.key.eq_ignore_ascii_case(key))
+            .map(|item| String::from_utf8_lossy(&item.value).to_string())
+    }
+
+    pub fn keys(&self) -> Vec<String> {
+        self.items.iter().map(|item| item.key.clone()).collect()
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) {
+        self.remove(key);
+        self.items.push(ApeItem { key: key.to_string(), flags: 0, value: value.as_bytes().to_vec() });
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        self.items.retain(|item| !item.key.eq_ignore_ascii_case(key));
+    }
+
+    /// Keeps only the items whose key passes `keep`.
+    pub fn retain(&mut self, keep: impl Fn(&str) -> bool) {
+        self.items.retain(|item| keep(&item.key));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut items = Vec::new();
+        for item in &self.items {
+            items.extend((item.value.len() as u32).to_le_bytes());
+            items.extend(item.flags.to_le_bytes());
+            items.extend(item.key.as_bytes());
+            items.push(0);
+            items.extend(&item.value);
+        }
+
+        let mut res = Vec::with_capacity(items.len() + 2 * HEADER_SIZE);
+        res.extend(self.header(items.len(), HAS_HEADER | IS_HEADER));
+        res.extend(items.iter());
+        res.extend(self.header(items.len(), HAS_HEADER));
+        res
+    }
+
+    fn header(&self, items_size: usize, flags: u32) -> Vec<u8> {
+        let mut res = Vec::with_capacity(HEADER_SIZE);
+        res.extend(PREAMBLE);
+        res.extend(VERSION.to_le_bytes());
+        // the size counts the items and the footer, but not the header
+        res.extend(((items_size + HEADER_SIZE) as u32).to_le_bytes());
+        res.extend((self.items.len() as u32).to_le_bytes());
+        res.extend(flags.to_le_bytes());
+        res.extend([0; 8]);
+        res
+    }
+}
+
+/// Replaces the APE tag of the file with `tag`, or strips it when the tag is empty.
+pub fn write_ape_tag(data: &mut Vec<u8>, tag: &ApeTag) {
+    let location = match ApeTag::read(data) {
+        Some((_, location)) => location,
+        None => id3v1_start(data)..id3v1_start(data),
+    };
+    let bytes = if tag.is_empty() { Vec::new() } else { tag.to_bytes() };
+    data.splice(location, bytes);
+}
+
+/// Where the tags at the end of the file begin, i.e. where the audio ends.
+pub fn trailing_tags_start(data: &[u8]) -> usize {
+    match ApeTag::read(data) {
+        Some((_, location)) => location.start,
+        None => id3v1_start(data),
+    }
+}
+
+/// How many bytes the APE and ID3v1 tags take at the end of the file, given at least the last `TRAILER_SIZE` bytes.
+pub(crate) fn trailing_tags_length(tail: &[u8]) -> usize {
+    let end = id3v1_start(tail);
+    let footer = match end.checked_sub(HEADER_SIZE).and_then(|start| tail.get(start..end)) {
+        Some(footer) if footer.starts_with(PREAMBLE) => footer,
+        _ => return tail.len() - end,
+    };
+    // the size covers the items and the footer, but not the header
+    let header = if read_u32(footer, 20) & HAS_HEADER != 0 { HEADER_SIZE } else { 0 };
+    tail.len() - end + read_u32(footer, 12) as usize + header
+}
+
+pub(crate) fn id3v1_start(data: &[u8]) -> usize {
+    match data.len().checked_sub(ID3V1_SIZE) {
+        Some(start) if data[start..].starts_with(b"TAG") => start,
+        _ => data.len(),
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().expect("To be 4 bytes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // an APE tag without a header and one item, as some taggers write it
+    fn footer_only_tag() -> Vec<u8> {
+        let mut res = Vec::new();
+        res.extend(5u32.to_le_bytes());
+        res.extend(0u32.to_le_bytes());
+        res.extend(b"MP3GAIN_MINMAX\0");
+        res.extend(b"0,210");
+        res.extend(PREAMBLE);
+        res.extend(VERSION.to_le_bytes());
+        res.extend((8 + 15 + 5 + HEADER_SIZE as u32).to_le_bytes());
+        res.extend(1u32.to_le_bytes());
+        res.extend(0u32.to_le_bytes());
+        res.extend([0; 8]);
+        res
+    }
+
+    fn id3v1_tag() -> Vec<u8> {
+        let mut res = b"TAG".to_vec();
+        res.resize(ID3V1_SIZE, b' ');
+        res
+    }
+
+    #[test]
+    fn reads_a_tag_without_a_header() {
+        let mut data = b"audio".to_vec();
+        data.extend(footer_only_tag());
+
+        let (tag, location) = ApeTag::read(&data).expect("To be a tag");
+        assert_eq!(tag.keys(), ["MP3GAIN_MINMAX"]);
+        assert_eq!(tag.get("mp3gain_minmax").as_deref(), Some("0,210"));
+        assert_eq!(location, 5..data.len());
+    }
+
+    #[test]
+    fn reads_a_tag_before_an_id3v1_tag() {
+        let mut data = b"audio".to_vec();
+        data.extend(footer_only_tag());
+        data.extend(id3v1_tag());
+
+        let (_, location) = ApeTag::read(&data).expect("To be a tag");
+        assert_eq!(location, 5..data.len() - ID3V1_SIZE);
+        assert_eq!(trailing_tags_start(&data), 5);
+    }
+
+    #[test]
+    fn round_trips_through_write() {
+        let mut data = b"audio".to_vec();
+        data.extend(id3v1_tag());
+        let mut tag = ApeTag::default();
+        tag.set("MP3GAIN_UNDO", "+002,+002,N");
+        tag.set("REPLAYGAIN_TRACK_GAIN", "-6.00 dB");
+        write_ape_tag(&mut data, &tag);
+
+        assert!(data.starts_with(b"audio"));
+        assert!(data.ends_with(&id3v1_tag()));
+        let (read, location) = ApeTag::read(&data).expect("To be a tag");
+        assert_eq!(read.keys(), ["MP3GAIN_UNDO", "REPLAYGAIN_TRACK_GAIN"]);
+        assert_eq!(read.get("MP3GAIN_UNDO").as_deref(), Some("+002,+002,N"));
+        assert_eq!(&data[location.start..location.start + 8], PREAMBLE);
+
+        tag.set("mp3gain_undo", "-001,-001,N");
+        write_ape_tag(&mut data, &tag);
+        let (read, _) = ApeTag::read(&data).expect("To be a tag");
+        assert_eq!(read.keys(), ["REPLAYGAIN_TRACK_GAIN", "mp3gain_undo"]);
+
+        write_ape_tag(&mut data, &ApeTag::default());
+        assert_eq!(data, [b"audio".to_vec(), id3v1_tag()].concat());
+    }
+
+    #[test]
+    fn measures_the_trailing_tags_from_the_tail() {
+        let mut tag = ApeTag::default();
+        tag.set("MP3GAIN_MINMAX", "0,210");
+        let mut data = vec![0xff; 300];
+        write_ape_tag(&mut data, &tag);
+        data.extend(id3v1_tag());
+        let tail = &data[data.len() - TRAILER_SIZE..];
+        assert_eq!(trailing_tags_length(tail), data.len() - 300);
+
+        let mut data = vec![0xff; 300];
+        data.extend(footer_only_tag());
+        assert_eq!(trailing_tags_length(&data[data.len() - TRAILER_SIZE..]), data.len() - 300);
+
+        let data = [vec![0xff; 300], id3v1_tag()].concat();
+        assert_eq!(trailing_tags_length(&data[data.len() - TRAILER_SIZE..]), ID3V1_SIZE);
+        assert_eq!(trailing_tags_length(&[0xff; TRAILER_SIZE]), 0);
+    }
+
+    #[test]
+    fn rejects_a_truncated_tag() {
+        let data = footer_only_tag();
+        assert!(ApeTag::read(&data[10..]).is_none());
+        assert!(ApeTag::read(b"APETAGEX").is_none());
+    }
+}
@@ -0,0 +1,145 @@
+use std::error::Error;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::args::ARGS;
+use crate::ffmpeg::{probe_audio_stream, run};
+use crate::gain::{avoid_clipping, clips};
+use crate::loudness_types::{Decibel, LinearLoudness};
+use crate::mp3_gain::{is_mp3, prepare_lossless_gain};
+use crate::path_display::escape_path;
+use crate::tags::{get_file_extension, remove_rg_tags, rg_tag_removal};
+
+// the limiter looks at samples, so it runs oversampled to catch the inter-sample peaks as well
+const LIMITER_OVERSAMPLING: u32 = 4;
+const LOSSLESS_CODECS: [&str; 3] = ["flac", "alac", "wavpack"];
+// without --apply-dir the copies are written next to the originals as <name>.normalized.<extension>
+const NORMALIZED_SUFFIX: &str = "normalized";
+
+/// Writes a copy of the file with `gain` applied to the audio, re-encoded with the codec of the original.
+/// The true peak stays below `--maxtpl`, either by lowering the gain or with `--limiter`.
+pub fn write_normalized(filepath: &Path, gain: Decibel, true_peak: LinearLoudness) -> Result<PathBuf, Box<dyn Error>> {
+    let output = normalized_path(filepath)?;
+    if ARGS.lossless {
+        write_lossless(filepath, &output, gain, true_peak)?;
+        return Ok(output);
+    }
+    let stream: Vec<(String, String)> = probe_audio_stream(filepath, "stream=codec_name,bit_rate,sample_rate")?;
+    let entry = |key: &str| stream.iter().find(|(name, _)| name == key).map(|(_, value)| value.as_str());
+    let codec = entry("codec_name").unwrap_or_default();
+
+    let limit = clips(gain, true_peak);
+    let gain = if limit && !ARGS.limiter { avoid_clipping(gain, true_peak) } else { gain };
+    let mut filters = format!("volume={:.2}dB", gain.as_f64());
+    if limit && ARGS.limiter {
+        let rate: u32 = entry("sample_rate").ok_or("Cannot find the sample rate of the audio stream")?.parse()?;
+        filters.push_str(&format!(",aresample={},alimiter=limit={:.6}:level=0,aresample={}",
+                                  rate * LIMITER_OVERSAMPLING, ARGS.maxtlp.as_linear().as_f64(), rate));
+    }
+
+    let mut args: Vec<OsString> = vec![
+        "ffmpeg".into(),
+        "-hide_banner".into(),
+        "-y".into(),
+        "-i".into(),
+        filepath.into(),
+        "-map".into(),
+        "0".into(),
+        // cover art and other streams are copied as they are
+        "-codec".into(),
+        "copy".into(),
+    ];
+    if let Some(encoder) = encoder(codec) {
+        args.extend(["-codec:a".into(), encoder.into()]);
+    }
+    if let Some(bit_rate) = entry("bit_rate").filter(|_| !LOSSLESS_CODECS.contains(&codec)) {
+        args.extend(["-b:a".into(), bit_rate.into()]);
+    }
+    args.extend(["-af".into(), filters.into()]);
+    // the gain is now part of the audio, so the ReplayGain tags of the original no longer apply
+    args.extend(rg_tag_removal(get_file_extension(filepath)).into_iter().map(OsString::from));
+    args.push(output.clone().into());
+
+    run(&args)?;
+    Ok(output)
+}
+
+/// Copies an MP3 file and changes the global gain of its frames, so the audio is not re-encoded.
+fn write_lossless(filepath: &Path, output: &Path, gain: Decibel, true_peak: LinearLoudness) -> Result<(), Box<dyn Error>> {
+    if !is_mp3(filepath) {
+        // the global_gain of the second AAC channel sits behind the Huffman coded spectral data of the first one
+        return Err(format!("Cannot apply the gain to {} losslessly, only MP3 files support it, drop --lossless to re-encode it", escape_path(filepath)).into());
+    }
+
+    let gain = if clips(gain, true_peak) { avoid_clipping(gain, true_peak) } else { gain };
+    fs::copy(filepath, output)?;
+    // the gain is now part of the audio, so the ReplayGain tags of the original no longer apply
+    remove_rg_tags(output)?;
+    prepare_lossless_gain(output, gain, true)?.apply(output)
+}
+
+/// Whether the file is a normalized copy written next to its original, which is not scanned again.
+pub fn is_normalized_copy(path: &Path) -> bool {
+    path.file_stem().and_then(|stem| Path::new(stem).extension()).is_some_and(|suffix| suffix == NORMALIZED_SUFFIX)
+}
+
+fn normalized_path(path: &Path) -> Result<PathBuf, Box<dyn Error>> {
+    let output = match &ARGS.apply_dir {
+        Some(directory) => directory.join(path.file_name().ok_or("File does not have a name")?),
+        None => path.with_extension(format!("{}.{}", NORMALIZED_SUFFIX, get_file_extension(path))),
+    };
+
+    if output == path {
+        return Err(format!("Refusing to overwrite {} with its normalized copy", escape_path(path)).into());
+    }
+    Ok(output)
+}
+
+fn encoder(codec: &str) -> Option<&'static str> {
+    match codec {
+        "flac" => Some("flac"),
+        "alac" => Some("alac"),
+        "wavpack" => Some("wavpack"),
+        "mp3" => Some("libmp3lame"),
+        "aac" => Some("aac"),
+        "vorbis" => Some("libvorbis"),
+        "opus" => Some("libopus"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognizes_normalized_copies() {
+        assert!(is_normalized_copy(Path::new("/music/a.normalized.flac")));
+        assert!(is_normalized_copy(Path::new("b.c.normalized.mp3")));
+        assert!(!is_normalized_copy(Path::new("/music/normalized.flac")));
+        assert!(!is_normalized_copy(Path::new("/music/a.flac")));
+        assert!(!is_normalized_copy(Path::new("/music/a.normalized")));
+    }
+
+    #[test]
+    fn writes_the_copy_next_to_the_original() {
+        let output = normalized_path(Path::new("/music/a.b.flac")).expect("To be the normalized path");
+        assert_eq!(output, Path::new("/music/a.b.normalized.flac"));
+        assert!(is_normalized_copy(&output));
+    }
+
+    #[test]
+    fn re_encodes_with_the_codec_of_the_original() {
+        assert_eq!(encoder("mp3"), Some("libmp3lame"));
+        assert_eq!(encoder("opus"), Some("libopus"));
+        assert_eq!(encoder("pcm_s16le"), None);
+    }
+
+    #[test]
+    fn applies_the_gain_losslessly_to_mp3_only() {
+        let error = write_lossless(Path::new("a.m4a"), Path::new("b.m4a"), Decibel::new(-3.0), LinearLoudness::new(0.5))
+            .expect_err("To refuse the AAC file");
+        assert!(error.to_string().starts_with("Cannot apply the gain to a.m4a losslessly"));
+    }
+}
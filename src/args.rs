@@ -1,20 +1,41 @@
 use std::collections::HashSet;
 use std::fmt::{Debug, Display, Formatter};
 use std::fs;
-use std::iter::Scan;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::str::FromStr;
 
-use clap::Parser;
+use clap::{AppSettings, Parser};
 use lazy_static::lazy_static;
 
+use crate::apply_gain::is_normalized_copy;
+use crate::compliance::LoudnessSpec;
+#[cfg(not(test))]
+use crate::config::args_with_config;
+use crate::cue_sheet::is_cue_sheet;
 use crate::loudness_types::{Decibel, LoudnessUnitFullScale};
+use crate::path_filter::{PathFilter, PathPattern};
+use crate::playlist::{is_playlist, read_playlist};
+use crate::replaygain_scanner::ScanOptions;
+use crate::path_display::escape_path;
+use crate::tag_backup::is_backup;
+use crate::tag_filter::TagPattern;
+use crate::tags::get_file_extension;
 
+#[cfg(not(test))]
 lazy_static! {
-    pub static ref ARGS: Args = Args::parse();
+    pub static ref ARGS: Args = args_with_config();
 }
 
+// unit tests run with the default options, neither the arguments of the test harness nor the config files apply
+#[cfg(test)]
+lazy_static! {
+    pub static ref ARGS: Args = Args::parse_from([env!("CARGO_PKG_NAME")]);
+}
+
+/// The file name standing for the audio piped to stdin.
+pub const STDIN: &str = "-";
+
 pub enum ScanMode {
     DontWriteTags,
     DeleteTags,
@@ -64,10 +85,509 @@ impl FromStr for ScanMode {
     }
 }
 
+pub enum PeakMode {
+    TruePeak,
+    SamplePeak,
+}
+
+impl Display for PeakMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let res = match self {
+            PeakMode::TruePeak => "true",
+            PeakMode::SamplePeak => "sample",
+        };
+        write!(f, "{}", res)
+    }
+}
+
+impl Debug for PeakMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl FromStr for PeakMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "true" => Ok(PeakMode::TruePeak),
+            "sample" => Ok(PeakMode::SamplePeak),
+            _ => Err(format!("Cannot parse {} into a peak mode.", s)),
+        }
+    }
+}
+
+pub enum TimelineFormat {
+    Csv,
+    Json,
+}
+
+impl Display for TimelineFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let res = match self {
+            TimelineFormat::Csv => "csv",
+            TimelineFormat::Json => "json",
+        };
+        write!(f, "{}", res)
+    }
+}
+
+impl Debug for TimelineFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl FromStr for TimelineFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(TimelineFormat::Csv),
+            "json" => Ok(TimelineFormat::Json),
+            _ => Err(format!("Cannot parse {} into a timeline format.", s)),
+        }
+    }
+}
+
+pub enum ReportMode {
+    Debug,
+    Human,
+}
+
+impl Display for ReportMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let res = match self {
+            ReportMode::Debug => "debug",
+            ReportMode::Human => "human",
+        };
+        write!(f, "{}", res)
+    }
+}
+
+impl Debug for ReportMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl FromStr for ReportMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "debug" => Ok(ReportMode::Debug),
+            "human" => Ok(ReportMode::Human),
+            _ => Err(format!("Cannot parse {} into a report mode.", s)),
+        }
+    }
+}
+
+pub enum SilenceHandling {
+    Skip,
+    Zero,
+    Error,
+}
+
+impl Display for SilenceHandling {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let res = match self {
+            SilenceHandling::Skip => "skip",
+            SilenceHandling::Zero => "zero",
+            SilenceHandling::Error => "error",
+        };
+        write!(f, "{}", res)
+    }
+}
+
+impl Debug for SilenceHandling {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl FromStr for SilenceHandling {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(SilenceHandling::Skip),
+            "zero" => Ok(SilenceHandling::Zero),
+            "error" => Ok(SilenceHandling::Error),
+            _ => Err(format!("Cannot parse {} into a silence handling mode.", s)),
+        }
+    }
+}
+
+pub enum WalkErrorHandling {
+    Ignore,
+    Warn,
+    Error,
+}
+
+impl Display for WalkErrorHandling {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let res = match self {
+            WalkErrorHandling::Ignore => "ignore",
+            WalkErrorHandling::Warn => "warn",
+            WalkErrorHandling::Error => "error",
+        };
+        write!(f, "{}", res)
+    }
+}
+
+impl Debug for WalkErrorHandling {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl FromStr for WalkErrorHandling {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(WalkErrorHandling::Ignore),
+            "warn" => Ok(WalkErrorHandling::Warn),
+            "error" => Ok(WalkErrorHandling::Error),
+            _ => Err(format!("Cannot parse {} into a walk error handling mode.", s)),
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub enum SampleRateStrategy {
+    #[default]
+    Native,
+    Reject,
+    Resample,
+}
+
+impl Display for SampleRateStrategy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let res = match self {
+            SampleRateStrategy::Native => "native",
+            SampleRateStrategy::Reject => "reject",
+            SampleRateStrategy::Resample => "resample",
+        };
+        write!(f, "{}", res)
+    }
+}
+
+impl Debug for SampleRateStrategy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl FromStr for SampleRateStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "native" => Ok(SampleRateStrategy::Native),
+            "reject" => Ok(SampleRateStrategy::Reject),
+            "resample" => Ok(SampleRateStrategy::Resample),
+            _ => Err(format!("Cannot parse {} into a sample rate strategy.", s)),
+        }
+    }
+}
+
+pub enum RawFormat {
+    S16le,
+    S24le,
+    S32le,
+    F32le,
+}
+
+impl Display for RawFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let res = match self {
+            RawFormat::S16le => "s16le",
+            RawFormat::S24le => "s24le",
+            RawFormat::S32le => "s32le",
+            RawFormat::F32le => "f32le",
+        };
+        write!(f, "{}", res)
+    }
+}
+
+impl Debug for RawFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl FromStr for RawFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "s16le" => Ok(RawFormat::S16le),
+            "s24le" => Ok(RawFormat::S24le),
+            "s32le" => Ok(RawFormat::S32le),
+            "f32le" => Ok(RawFormat::F32le),
+            _ => Err(format!("Cannot parse {} into a raw sample format.", s)),
+        }
+    }
+}
+
+pub enum GainType {
+    Track,
+    Album,
+}
+
+impl Display for GainType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let res = match self {
+            GainType::Track => "track",
+            GainType::Album => "album",
+        };
+        write!(f, "{}", res)
+    }
+}
+
+impl Debug for GainType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl FromStr for GainType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "track" => Ok(GainType::Track),
+            "album" => Ok(GainType::Album),
+            _ => Err(format!("Cannot parse {} into a gain type.", s)),
+        }
+    }
+}
+
+pub enum PictureHandling {
+    Keep,
+    Strip,
+}
+
+impl Display for PictureHandling {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let res = match self {
+            PictureHandling::Keep => "keep",
+            PictureHandling::Strip => "strip",
+        };
+        write!(f, "{}", res)
+    }
+}
+
+impl Debug for PictureHandling {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl FromStr for PictureHandling {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(PictureHandling::Keep),
+            "strip" => Ok(PictureHandling::Strip),
+            _ => Err(format!("Cannot parse {} into a picture handling.", s)),
+        }
+    }
+}
+
+pub enum Id3v2Version {
+    V3,
+    V4,
+}
+
+impl Display for Id3v2Version {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let res = match self {
+            Id3v2Version::V3 => "3",
+            Id3v2Version::V4 => "4",
+        };
+        write!(f, "{}", res)
+    }
+}
+
+impl Id3v2Version {
+    pub fn major(&self) -> u8 {
+        match self {
+            Id3v2Version::V3 => 3,
+            Id3v2Version::V4 => 4,
+        }
+    }
+}
+
+impl Debug for Id3v2Version {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl FromStr for Id3v2Version {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "3" => Ok(Id3v2Version::V3),
+            "4" => Ok(Id3v2Version::V4),
+            _ => Err(format!("Cannot parse {} into an ID3v2 version.", s)),
+        }
+    }
+}
+
+pub enum ApeTagHandling {
+    Keep,
+    Write,
+    Strip,
+}
+
+impl Display for ApeTagHandling {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let res = match self {
+            ApeTagHandling::Keep => "keep",
+            ApeTagHandling::Write => "write",
+            ApeTagHandling::Strip => "strip",
+        };
+        write!(f, "{}", res)
+    }
+}
+
+impl Debug for ApeTagHandling {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl FromStr for ApeTagHandling {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(ApeTagHandling::Keep),
+            "write" => Ok(ApeTagHandling::Write),
+            "strip" => Ok(ApeTagHandling::Strip),
+            _ => Err(format!("Cannot parse {} into an APE tag handling.", s)),
+        }
+    }
+}
+
+pub enum Id3v1Handling {
+    Keep,
+    Strip,
+}
+
+impl Display for Id3v1Handling {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let res = match self {
+            Id3v1Handling::Keep => "keep",
+            Id3v1Handling::Strip => "strip",
+        };
+        write!(f, "{}", res)
+    }
+}
+
+impl Debug for Id3v1Handling {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl FromStr for Id3v1Handling {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(Id3v1Handling::Keep),
+            "strip" => Ok(Id3v1Handling::Strip),
+            _ => Err(format!("Cannot parse {} into an ID3v1 tag handling.", s)),
+        }
+    }
+}
+
+pub enum TagCase {
+    Auto,
+    Upper,
+    Lower,
+}
+
+impl Display for TagCase {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let res = match self {
+            TagCase::Auto => "auto",
+            TagCase::Upper => "upper",
+            TagCase::Lower => "lower",
+        };
+        write!(f, "{}", res)
+    }
+}
+
+impl Debug for TagCase {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl FromStr for TagCase {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(TagCase::Auto),
+            "upper" => Ok(TagCase::Upper),
+            "lower" => Ok(TagCase::Lower),
+            _ => Err(format!("Cannot parse {} into a tag case.", s)),
+        }
+    }
+}
+
 #[derive(Parser, Debug)]
-#[clap(author = "Sebastian Bartoszewicz")]
+#[clap(author = "Sebastian Bartoszewicz", setting = AppSettings::AllArgsOverrideSelf)]
 pub struct Args {
-    pub files: Vec<String>,
+    /// Files, directories or M3U, PLS and CUE playlists to scan, "-" reads an encoded stream from stdin
+    #[clap(parse(from_os_str))]
+    pub files: Vec<PathBuf>,
+
+    /// Only scan the files found in directories that match one of these globs, like "*.flac"
+    #[clap(long = "include", multiple_occurrences = true)]
+    pub include: Vec<PathPattern>,
+
+    /// Skip the files and directories matching one of these globs, like "Live/" or "**/demos/*.mp3"
+    #[clap(long = "exclude", multiple_occurrences = true)]
+    pub exclude: Vec<PathPattern>,
+
+    /// Also walk hidden files and directories and NAS metadata folders like @eaDir
+    #[clap(long = "hidden")]
+    pub hidden: bool,
+
+    /// Follow symbolic links to directories while walking them
+    #[clap(long = "follow-symlinks")]
+    pub follow_symlinks: bool,
+
+    /// Do not walk into directories on other file systems
+    #[clap(long = "one-file-system")]
+    pub one_file_system: bool,
+
+    /// What to do with unreadable directories, broken links and missing playlist entries: ignore, warn or error, which stops before scanning
+    #[clap(long = "walk-errors", default_value_t = WalkErrorHandling::Warn)]
+    pub walk_errors: WalkErrorHandling,
+
+    /// Treat every playlist as an album and write the album gain of its files
+    #[clap(long = "playlist-album")]
+    pub playlist_album: bool,
+
+    /// Profile of the config files to apply, on top of their options for every run
+    #[clap(long = "profile")]
+    pub profile: Option<String>,
+
+    /// Print the configuration after applying the config files and exit
+    #[clap(long = "print-config")]
+    pub print_config: bool,
 
     #[clap(short = 'q', long = "quiet")]
     pub quiet: bool,
@@ -78,12 +598,39 @@ pub struct Args {
     #[clap(short = 'K', long = "maxtpl", default_value_t = LoudnessUnitFullScale::new(- 1.0))]
     pub maxtlp: LoudnessUnitFullScale,
 
+    /// Gain added on top of the reference loudness
     #[clap(short = 'd', long = "pregain", default_value_t = Decibel::new(0.0))]
     pub pregain: Decibel,
 
-    #[clap(short = 'L', long = "lowercase")]
+    /// Reference loudness the gains are computed for. REPLAYGAIN_REFERENCE_LOUDNESS stores it raised by --pregain,
+    /// the loudness the gain leads to, which lets --retarget and --verify derive the loudness from the gain
+    #[clap(long = "reference", default_value_t = LoudnessUnitFullScale::new(- 18.0), allow_hyphen_values = true)]
+    pub reference: LoudnessUnitFullScale,
+
+    /// Recompute the gains from the stored ReplayGain tags for the current reference and pregain, without decoding
+    #[clap(long = "retarget")]
+    pub retarget: bool,
+
+    /// Scan the files and compare the results to their ReplayGain tags instead of writing them
+    #[clap(long = "verify", conflicts_with_all = & ["retarget", "restore", "mp3gain", "undo-mp3gain", "apply-gain"])]
+    pub verify: bool,
+
+    /// Check the files against a loudness spec instead of tagging them and print a JSON report:
+    /// ebu-r128, atsc-a85, spotify, apple, youtube or a custom one like target=-16,tolerance=1,true-peak=-1,range=12
+    #[clap(long = "check", conflicts_with_all = & ["verify", "retarget", "restore", "mp3gain", "undo-mp3gain", "apply-gain"])]
+    pub check: Option<LoudnessSpec>,
+
+    /// Largest difference in dB between the stored and the measured gain or peak that --verify accepts
+    #[clap(long = "tolerance", default_value_t = Decibel::new(0.1))]
+    pub tolerance: Decibel,
+
+    #[clap(short = 'L', long = "lowercase", conflicts_with = "tag-case")]
     pub lowercase_tags: bool,
 
+    /// Casing of the ReplayGain keys: "auto" (lowercase MP4 freeform atoms, uppercase elsewhere), "upper" or "lower"
+    #[clap(long = "tag-case", default_value_t = TagCase::Auto)]
+    pub tag_case: TagCase,
+
     #[clap(short = 'r', long = "track")]
     pub track: bool,
 
@@ -92,26 +639,201 @@ pub struct Args {
 
     #[clap(short = 'S', long = "striptags")]
     pub strip_tags: bool,
+
+    /// Tag to keep when stripping or removing tags, as a glob or a "re:" regex, can be given multiple times
+    #[clap(long = "keep-tag", multiple_occurrences = true)]
+    pub keep_tags: Vec<TagPattern>,
+
+    /// Tag to remove when writing, as a glob or a "re:" regex, can be given multiple times
+    #[clap(long = "remove-tag", multiple_occurrences = true)]
+    pub remove_tags: Vec<TagPattern>,
+
+    /// ID3v2 version of the tags written to MP3 files: 3 or 4
+    #[clap(short = 'I', long = "id3v2version", default_value_t = Id3v2Version::V4)]
+    pub id3v2_version: Id3v2Version,
+
+    /// Also write the track gain of MP3 files to an RVA2 frame (ID3v2.4 only)
+    #[clap(long = "rva2")]
+    pub rva2: bool,
+
+    /// APEv2 tags of MP3 files: "keep" them, also "write" the ReplayGain tags to them, or "strip" them
+    #[clap(long = "ape-tags", default_value_t = ApeTagHandling::Keep)]
+    pub ape_tags: ApeTagHandling,
+
+    /// ID3v1 tags of MP3 files: "keep" or "strip", -S strips them as well
+    #[clap(long = "id3v1", default_value_t = Id3v1Handling::Keep)]
+    pub id3v1: Id3v1Handling,
+
+    /// Also write the track gain of MP4 files as an iTunNORM (Sound Check) tag for Apple players
+    #[clap(long = "itunnorm")]
+    pub itunnorm: bool,
+
+    /// What to do with embedded cover art when writing: "keep" or "strip"
+    #[clap(long = "pictures", default_value_t = PictureHandling::Keep)]
+    pub pictures: PictureHandling,
+
+    /// Which peak is written to REPLAYGAIN_TRACK_PEAK: "true" (oversampled) or "sample"
+    #[clap(long = "peak", default_value_t = PeakMode::TruePeak)]
+    pub peak_mode: PeakMode,
+
+    /// Report true and sample peaks for every channel
+    #[clap(long = "channel-peaks")]
+    pub channel_peaks: bool,
+
+    /// Export the momentary and short-term loudness over time as "csv" or "json"
+    #[clap(long = "timeline")]
+    pub timeline: Option<TimelineFormat>,
+
+    /// Directory for the timeline exports, defaults to the directory of each scanned file
+    #[clap(long = "timeline-dir", parse(from_os_str))]
+    pub timeline_dir: Option<PathBuf>,
+
+    /// How scan results are printed: "debug" or "human" (includes the gated loudness statistics)
+    #[clap(long = "report", default_value_t = ReportMode::Debug)]
+    pub report: ReportMode,
+
+    /// What to do with silent or too short files: "skip" tagging, write a "zero" dB gain or "error" out
+    #[clap(long = "silence", default_value_t = SilenceHandling::Skip)]
+    pub silence: SilenceHandling,
+
+    /// Also look for CUE sheets next to the songs, besides the ones given as arguments, and measure the tracks they split a file into
+    #[clap(long = "cue-sheets")]
+    pub cue_sheets: bool,
+
+    /// Downmix surround files to stereo before measuring
+    #[clap(long = "downmix")]
+    pub downmix: bool,
+
+    /// Measure mono files as dual-mono, matching their loudness on stereo playback
+    #[clap(long = "dual-mono")]
+    pub dual_mono: bool,
+
+    /// Files sampled outside of 32 to 192 kHz are measured "native"ly, "reject"ed or "resample"d to 48/96 kHz
+    #[clap(long = "rate-strategy", default_value_t = SampleRateStrategy::Native)]
+    pub rate_strategy: SampleRateStrategy,
+
+    /// Read headerless samples in the given format (s16le, s24le, s32le, f32le) from stdin
+    #[clap(long = "format", requires_all = &["rate", "channels"])]
+    pub raw_format: Option<RawFormat>,
+
+    /// Sample rate of the raw input
+    #[clap(long = "rate")]
+    pub rate: Option<u32>,
+
+    /// Channel count of the raw input
+    #[clap(long = "channels")]
+    pub channels: Option<u32>,
+
+    /// Write a copy of every file with the "track" or "album" gain applied to the audio
+    #[clap(long = "apply-gain")]
+    pub apply_gain: Option<GainType>,
+
+    /// Directory for the files written by --apply-gain, defaults to "<name>.normalized.<ext>" next to the original,
+    /// which later scans skip
+    #[clap(long = "apply-dir", parse(from_os_str))]
+    pub apply_dir: Option<PathBuf>,
+
+    /// Keep the full gain with --apply-gain and limit the true peak to --maxtpl instead of lowering the gain
+    #[clap(long = "limiter")]
+    pub limiter: bool,
+
+    /// Write the --apply-gain copies of MP3 files losslessly in 1.5 dB global_gain steps instead of re-encoding them.
+    /// Other formats are refused, AAC included
+    #[clap(long = "lossless", requires = "apply-gain", conflicts_with = "limiter")]
+    pub lossless: bool,
+
+    /// Change the volume of MP3 files losslessly in 1.5 dB steps, like mp3gain, and keep the undo information in an APE tag
+    #[clap(long = "mp3gain")]
+    pub mp3gain: bool,
+
+    /// Revert the volume changes made by --mp3gain or mp3gain before scanning
+    #[clap(long = "undo-mp3gain")]
+    pub undo_mp3gain: bool,
+
+    /// Put back the ReplayGain tags saved before they were first changed, instead of scanning
+    #[clap(long = "restore")]
+    pub restore: bool,
+}
+
+impl Args {
+    pub fn scan_options(&self) -> ScanOptions {
+        ScanOptions {
+            downmix: self.downmix,
+            dual_mono: self.dual_mono,
+            rate_strategy: self.rate_strategy.clone(),
+        }
+    }
 }
 
-pub fn build_file_list(files: Vec<String>) -> Vec<String> {
+/// Whether the path stands for stdin rather than a file.
+pub fn is_stdin(path: &Path) -> bool {
+    path == Path::new(STDIN)
+}
+
+pub fn build_file_list(files: Vec<PathBuf>) -> Vec<PathBuf> {
+    // stdin is not a path, so it skips the checks below. Raw samples can only come from stdin,
+    // which can only be read once.
+    if ARGS.raw_format.is_some() || files.iter().any(|file| is_stdin(file)) {
+        if files.len() > 1 || files.iter().any(|file| !is_stdin(file)) {
+            println!("Stdin cannot be scanned together with other files");
+            exit(1);
+        }
+        return vec![PathBuf::from(STDIN)];
+    }
+
     check_for_invalid_paths(&files);
 
-    let expanded_directories = get_files_from_folders_recursively(files);
+    let (files, playlist_errors) = expand_playlists(files);
+    let expanded_directories = get_files_from_folders_recursively(files, playlist_errors);
     let absolute_paths = make_paths_absolute(expanded_directories);
 
     check_for_invalid_extension(absolute_paths)
 }
 
-fn get_files_from_folders_recursively(files: Vec<String>) -> Vec<String> {
-    files.into_iter().flat_map(recursively_expand_directory).collect()
+/// Replaces the playlists with the files they list. Entries that do not exist are reported like walk errors.
+fn expand_playlists(files: Vec<PathBuf>) -> (Vec<PathBuf>, Vec<String>) {
+    let mut res = Vec::new();
+    let mut errors = Vec::new();
+    for file in files {
+        if !is_playlist(&file) {
+            res.push(file);
+            continue;
+        }
+        match read_playlist(&file) {
+            Ok(entries) => for entry in entries {
+                if entry.exists() {
+                    res.push(entry);
+                } else {
+                    errors.push(format!("Cannot find {} listed in {}", escape_path(&entry), escape_path(&file)));
+                }
+            },
+            Err(err) => errors.push(format!("Cannot read the playlist {}: {}", escape_path(&file), err)),
+        }
+    }
+    (res, errors)
 }
 
-fn make_paths_absolute(files: Vec<String>) -> Vec<String> {
-    files.into_iter().map(|file| fs::canonicalize(file).expect("To be an absolute path.").to_str().expect("To be a string slice.").to_string()).collect()
+fn get_files_from_folders_recursively(files: Vec<PathBuf>, mut errors: Vec<String>) -> Vec<PathBuf> {
+    let (files, walk_errors): (Vec<_>, Vec<_>) = files.into_iter().map(recursively_expand_directory).unzip();
+    errors.extend(walk_errors.into_iter().flatten());
+    if !errors.is_empty() {
+        match ARGS.walk_errors {
+            WalkErrorHandling::Ignore => {}
+            WalkErrorHandling::Warn => errors.iter().for_each(|err| eprintln!("{}", err)),
+            WalkErrorHandling::Error => {
+                errors.iter().for_each(|err| eprintln!("{}", err));
+                exit(1);
+            }
+        }
+    }
+    files.into_iter().flatten().collect()
 }
 
-fn check_for_invalid_extension(paths: Vec<String>) -> Vec<String> {
+fn make_paths_absolute(files: Vec<PathBuf>) -> Vec<PathBuf> {
+    files.into_iter().map(|file| fs::canonicalize(file).expect("To be an absolute path.")).collect()
+}
+
+fn check_for_invalid_extension(paths: Vec<PathBuf>) -> Vec<PathBuf> {
     let valid_extensions = HashSet::from([
         // "aiff",
         // "aif",
@@ -135,37 +857,89 @@ fn check_for_invalid_extension(paths: Vec<String>) -> Vec<String> {
     ]);
 
     paths.into_iter().filter(|path| {
-        let extension = Path::new(&path).extension().expect("To be a file extension").to_str().expect("To be a string slice");
-        return if !valid_extensions.contains(extension) {
+        // CUE sheets split the audio files instead of being scanned, the tag backups and normalized copies are ours
+        if is_cue_sheet(path) || is_backup(path) || is_normalized_copy(path) {
+            false
+        } else if !valid_extensions.contains(get_file_extension(path)) {
             if !ARGS.quiet {
-                println!("Ignoring the following file due to an unsupported extension: {}", path);
+                println!("Ignoring the following file due to an unsupported extension: {}", escape_path(path));
             }
             false
         } else {
             true
-        };
+        }
     }).collect()
 }
 
-fn recursively_expand_directory(file: String) -> Vec<String> {
-    let path = Path::new(&file);
-    let mut res: Vec<String> = Vec::new();
-    // TODO: Fix this to not ignore errors
-    for entry in walkdir::WalkDir::new(path).into_iter().filter_map(|e| e.ok()).filter(|e| e.path().is_file()) {
-        res.push(entry.path().to_str().expect("To be a string slice").to_string());
+/// The files below a path along with the paths that could not be walked.
+fn recursively_expand_directory(path: PathBuf) -> (Vec<PathBuf>, Vec<String>) {
+    let mut filter = PathFilter::new(&path);
+    let mut res: Vec<PathBuf> = Vec::new();
+    let mut errors: Vec<String> = Vec::new();
+    let walker = walkdir::WalkDir::new(&path)
+        .follow_links(ARGS.follow_symlinks)
+        .same_file_system(ARGS.one_file_system);
+    let entries: Vec<_> = walker.into_iter().filter_entry(|e| filter.keep_entry(e)).filter_map(|e| match e {
+        Ok(entry) => Some(entry),
+        Err(err) => {
+            errors.push(format!("Cannot walk {}: {}", escape_path(err.path().unwrap_or(&path)), err));
+            None
+        }
+    }).collect();
+    for entry in entries {
+        let is_file = entry.path().is_file();
+        // links that are not followed still lead to files, but a broken one leads nowhere
+        if entry.path_is_symlink() && !entry.path().exists() {
+            errors.push(format!("Cannot walk {}: the symbolic link is broken", escape_path(entry.path())));
+        } else if is_file && (entry.depth() == 0 || filter.includes(entry.path())) {
+            res.push(entry.into_path());
+        }
     }
-    res
+    (res, errors)
 }
 
-fn check_for_invalid_paths(files: &[String]) {
+fn check_for_invalid_paths(files: &[PathBuf]) {
     let invalid_files: Vec<_> = files
         .iter()
-        .filter(|file| !Path::new(file).exists())
+        .filter(|file| !file.exists())
         .collect();
     if !invalid_files.is_empty() {
         invalid_files
             .iter()
-            .for_each(|file| println!("File not found: {}", file));
+            .for_each(|file| println!("File not found: {}", escape_path(file)));
         exit(1);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+
+    #[test]
+    fn walks_the_files_and_reports_broken_links() {
+        let directory = tempfile::tempdir().expect("To create a temp dir");
+        let root = directory.path();
+        fs::create_dir_all(root.join("artist/album")).expect("To create the album directory");
+        for file in ["artist/album/a.flac", "b.mp3"] {
+            fs::write(root.join(file), b"").expect("To write the song");
+        }
+        symlink(root.join("missing.flac"), root.join("broken.flac")).expect("To create the link");
+        // without --follow-symlinks a link to a directory is not followed, so the album is found once
+        symlink(root.join("artist"), root.join("linked")).expect("To create the link");
+
+        let (mut files, errors) = recursively_expand_directory(root.to_path_buf());
+        files.sort();
+        assert_eq!(files, [root.join("artist/album/a.flac"), root.join("b.mp3")]);
+        assert_eq!(errors, [format!("Cannot walk {}: the symbolic link is broken", escape_path(&root.join("broken.flac")))]);
+    }
+
+    #[test]
+    fn walks_a_single_file() {
+        let directory = tempfile::tempdir().expect("To create a temp dir");
+        let song = directory.path().join("a.flac");
+        fs::write(&song, b"").expect("To write the song");
+        assert_eq!(recursively_expand_directory(song.clone()), (vec![song], Vec::new()));
+    }
+}
@@ -1,21 +1,221 @@
-use rayon::prelude::{IntoParallelIterator, ParallelIterator};
+use std::path::Path;
+use std::process::exit;
 
-use loudgain_rust::args::ARGS;
+use rayon::prelude::{IndexedParallelIterator, IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
+
+use loudgain_rust::apply_gain::write_normalized;
+use loudgain_rust::args::{ARGS, GainType, Id3v2Version, is_stdin, ScanMode, SilenceHandling};
 use loudgain_rust::args::build_file_list;
-use loudgain_rust::decode_audio::decode_file;
-use loudgain_rust::replaygain_scanner::{get_track_gain, scan_file, TrackGain};
+use loudgain_rust::compliance::{check_compliance, Compliance, format_compliance_report};
+use loudgain_rust::config::config_files;
+use loudgain_rust::cue_sheet::{find_cue_sheets, save_cue_tags, scan_cue_sheet};
+use loudgain_rust::decode_audio::{decode_file, decode_stdin, read_raw_stdin};
+use loudgain_rust::path_display::escape_path;
+use loudgain_rust::mp3_gain::{is_mp3, prepare_lossless_gain, undo_lossless_gain};
+use loudgain_rust::replaygain_scanner::{get_album_gain, get_track_gain, scan_file, ScanResult, TrackGain};
+use loudgain_rust::playlist::playlist_albums;
+use loudgain_rust::report::print_report;
+use loudgain_rust::retarget::retarget;
+use loudgain_rust::tag_backup::restore_tags;
 use loudgain_rust::tags::save_tags;
+use loudgain_rust::timeline::export_timeline;
+use loudgain_rust::verify::verify;
 
 fn main() {
+    if ARGS.print_config {
+        config_files(&ARGS.files).iter().for_each(|file| println!("# {}", escape_path(file)));
+        println!("{:#?}", *ARGS);
+        return;
+    }
+    if ARGS.rva2 && matches!(ARGS.id3v2_version, Id3v2Version::V3) {
+        eprintln!("RVA2 frames only exist in ID3v2.4, use -I 4");
+        exit(1);
+    }
     let songs = build_file_list(ARGS.files.clone());
-    let scan_results: Vec<TrackGain> = songs.into_par_iter().map(|song| {
-        let decoded = decode_file(&song).expect("To be a decoding result");
-        let scan = scan_file(decoded).expect("To be a scan result.");
-        get_track_gain(song, scan)
+    if ARGS.restore {
+        songs.into_par_iter().filter(|song| !is_stdin(song)).for_each(|song| {
+            let restored = restore_tags(&song).expect("To be a song with its ReplayGain tags restored.");
+            if restored && !ARGS.quiet {
+                println!("Restored the ReplayGain tags of {}", escape_path(&song));
+            }
+        });
+        return;
+    }
+    if ARGS.retarget {
+        if matches!(ARGS.scan_mode, ScanMode::DontWriteTags) {
+            eprintln!("--retarget only rewrites tags, choose a tag mode with -s");
+            exit(1);
+        }
+        let failed = songs.into_par_iter().filter(|song| !is_stdin(song)).filter(|song| match retarget(song) {
+            Ok(track) => {
+                print_report(&track);
+                save_tags(&track).expect("To work");
+                false
+            }
+            Err(err) => {
+                eprintln!("Cannot retarget {}: {}", escape_path(song), err);
+                true
+            }
+        }).count();
+        exit(if failed == 0 { 0 } else { 1 });
+    }
+
+    // the tracks of a single file album are measured from its CUE sheet instead of the whole file at once,
+    // the stored tags to verify are those of whole files
+    let cue_sheets: Vec<_> = if ARGS.verify { Vec::new() } else { find_cue_sheets(&ARGS.files, &songs) };
+    // the gain of the audio file is shared by all of its tracks
+    if let Some(sheet) = cue_sheets.iter().find(|sheet| ARGS.mp3gain && is_mp3(&sheet.audio) || matches!(ARGS.apply_gain, Some(GainType::Track))) {
+        eprintln!("Cannot change the gain of {} track by track as {} splits it, use --apply-gain album instead", escape_path(&sheet.audio), escape_path(&sheet.path));
+        exit(1);
+    }
+    let songs: Vec<_> = songs.into_iter().filter(|song| !cue_sheets.iter().any(|sheet| &sheet.audio == song)).collect();
+
+    let scanned: Vec<Result<Vec<(TrackGain, ScanResult)>, String>> = songs.into_par_iter().map(|song| {
+        undo_mp3gain(&song);
+        let decoded = match &ARGS.raw_format {
+            Some(format) => read_raw_stdin(format, ARGS.channels.expect("To be a channel count"), ARGS.rate.expect("To be a sample rate")),
+            None if is_stdin(&song) => decode_stdin(),
+            None => decode_file(&song),
+        }.expect("To be a decoding result");
+        let scan = scan_file(decoded, &ARGS.scan_options()).map_err(|err| format!("Cannot scan {}: {}", escape_path(&song), err))?;
+        if let Some(format) = &ARGS.timeline {
+            export_timeline(&song, &scan, format).expect("To be a written loudness timeline.");
+        }
+        Ok(vec![(get_track_gain(song, &scan), scan)])
+    }).chain(cue_sheets.par_iter().map(|sheet| {
+        undo_mp3gain(&sheet.audio);
+        let tracks = scan_cue_sheet(sheet).map_err(|err| format!("Cannot scan the tracks of {}: {}", escape_path(&sheet.path), err))?;
+        if let Some(format) = &ARGS.timeline {
+            for (track, (_, scan)) in sheet.tracks.iter().zip(&tracks) {
+                export_timeline(&sheet.track_file(track.number), scan, format).expect("To be a written loudness timeline.");
+            }
+        }
+        Ok(tracks)
+    })).collect();
+    let mut scans: Vec<(TrackGain, ScanResult)> = Vec::new();
+    let mut failed = false;
+    for result in scanned {
+        match result {
+            Ok(tracks) => scans.extend(tracks),
+            Err(err) => {
+                eprintln!("{}", err);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        exit(1);
+    }
+
+    if let Some(spec) = &ARGS.check {
+        let results: Vec<_> = scans.iter().map(|(track, scan)| check_compliance(&track.filepath, scan, spec)).collect();
+        if !ARGS.quiet {
+            results.iter().filter(|result| !result.passes()).for_each(|result| eprintln!("{} fails {}: {}", escape_path(&result.filepath), spec, result.failures.join(", ")));
+        }
+        println!("{}", format_compliance_report(&results, spec));
+        exit(if results.iter().all(Compliance::passes) { 0 } else { 1 });
+    }
+
+    let album = get_album_gain(scans.iter().map(|(_, scan)| scan));
+    if ARGS.playlist_album {
+        let files: Vec<_> = scans.iter().map(|(track, _)| track.filepath.clone()).collect();
+        // a file in several playlists is tagged with the album of the last one
+        for members in playlist_albums(&ARGS.files, &files) {
+            let playlist_album = get_album_gain(scans.iter().filter(|(track, _)| members.contains(&track.filepath)).map(|(_, scan)| scan));
+            scans.iter_mut().filter(|(track, _)| members.contains(&track.filepath)).for_each(|(track, _)| track.album = Some(playlist_album));
+        }
+    }
+    let scan_results: Vec<TrackGain> = scans.into_iter().map(|(track, _)| track).collect();
+
+    if matches!(ARGS.silence, SilenceHandling::Error) {
+        let unmeasurable: Vec<_> = scan_results.iter().filter(|res| !res.status.is_measured()).collect();
+        if !unmeasurable.is_empty() {
+            unmeasurable.iter().for_each(|res| eprintln!("Cannot measure the loudness of {}: the file is {}", escape_path(&res.filepath), res.status));
+            exit(1);
+        }
+    }
+
+    if ARGS.verify {
+        let mismatches = scan_results.into_par_iter().filter(|res| !is_stdin(&res.filepath) && res.status.is_measured()).filter(|res| match verify(res) {
+            Ok(verification) if verification.matches() => {
+                if !ARGS.quiet {
+                    println!("{}", verification);
+                }
+                false
+            }
+            Ok(verification) => {
+                println!("{}", verification);
+                true
+            }
+            Err(err) => {
+                eprintln!("Cannot verify {}: {}", escape_path(&res.filepath), err);
+                true
+            }
+        }).count();
+        exit(if mismatches == 0 { 0 } else { 1 });
+    }
+
+    let mut sheet_tracks: Vec<Vec<TrackGain>> = cue_sheets.iter().map(|_| Vec::new()).collect();
+    let scan_results: Vec<TrackGain> = scan_results.into_iter().filter_map(|res| match cue_sheets.iter().position(|sheet| sheet.contains(&res.filepath)) {
+        Some(i) => {
+            sheet_tracks[i].push(res);
+            None
+        }
+        None => Some(res),
     }).collect();
+    cue_sheets.par_iter().zip(sheet_tracks).for_each(|(sheet, tracks)| {
+        tracks.iter().for_each(print_report);
+        save_cue_tags(sheet, &tracks).expect("To be a CUE sheet and a song with their ReplayGain tags written.");
+
+        // track gains are refused above, so this is the album gain of the sheet
+        if let (Some(_), Some(album)) = (&ARGS.apply_gain, tracks.iter().find(|res| res.status.is_measured()).and_then(|res| res.album)) {
+            let output = write_normalized(&sheet.audio, album.gain, album.true_peak).expect("To be a normalized copy of the song.");
+            if !ARGS.quiet {
+                println!("Wrote {}", escape_path(&output));
+            }
+        }
+    });
 
-    scan_results.into_par_iter().for_each(|res| {
-        println!("{:#?}", res);
+    scan_results.into_par_iter().for_each(|mut res| {
+        // rounding up would give back some of the headroom the -k gain was lowered for
+        let lossless = if ARGS.mp3gain && res.status.is_measured() && is_mp3(&res.filepath) {
+            Some(prepare_lossless_gain(&res.filepath, res.gain, ARGS.no_clip).expect("To be a lossless gain change."))
+        } else { None };
+        // the tags describe the audio after the lossless change
+        if let Some(change) = &lossless {
+            res.apply_gain(change.gain());
+        }
+
+        print_report(&res);
         save_tags(&res).expect("To work");
+
+        if let Some(change) = lossless {
+            change.apply(&res.filepath).expect("To be a song with its volume changed losslessly.");
+            if !ARGS.quiet {
+                println!("Applied {} losslessly to {}", change.gain(), escape_path(&res.filepath));
+            }
+        }
+
+        if let Some(gain_type) = &ARGS.apply_gain {
+            if !is_stdin(&res.filepath) && res.status.is_measured() {
+                let (gain, true_peak) = match gain_type {
+                    GainType::Track => (res.gain, res.true_peak),
+                    GainType::Album => res.album.map_or((album.gain, album.true_peak), |album| (album.gain, album.true_peak)),
+                };
+                let output = write_normalized(&res.filepath, gain, true_peak).expect("To be a normalized copy of the song.");
+                if !ARGS.quiet {
+                    println!("Wrote {}", escape_path(&output));
+                }
+            }
+        }
     })
 }
+
+fn undo_mp3gain(song: &Path) {
+    if ARGS.undo_mp3gain && is_mp3(song) {
+        let undone = undo_lossless_gain(song).expect("To be a song with the mp3gain changes reverted.");
+        if let (Some(gain), false) = (undone, ARGS.quiet) {
+            println!("Reverted {} of lossless gain on {}", gain, escape_path(song));
+        }
+    }
+}
@@ -0,0 +1,205 @@
+use ebur128::Channel;
+use symphonia::core::audio::Channels;
+
+const MINUS_3DB: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+// FLAC, WavPack and WAV store the channels in the order of the WAVEFORMATEXTENSIBLE speaker mask
+const WAVE_ORDER: [Channels; 26] = [
+    Channels::FRONT_LEFT,
+    Channels::FRONT_RIGHT,
+    Channels::FRONT_CENTRE,
+    Channels::LFE1,
+    Channels::REAR_LEFT,
+    Channels::REAR_RIGHT,
+    Channels::FRONT_LEFT_CENTRE,
+    Channels::FRONT_RIGHT_CENTRE,
+    Channels::REAR_CENTRE,
+    Channels::SIDE_LEFT,
+    Channels::SIDE_RIGHT,
+    Channels::TOP_CENTRE,
+    Channels::TOP_FRONT_LEFT,
+    Channels::TOP_FRONT_CENTRE,
+    Channels::TOP_FRONT_RIGHT,
+    Channels::TOP_REAR_LEFT,
+    Channels::TOP_REAR_CENTRE,
+    Channels::TOP_REAR_RIGHT,
+    Channels::REAR_LEFT_CENTRE,
+    Channels::REAR_RIGHT_CENTRE,
+    Channels::FRONT_LEFT_WIDE,
+    Channels::FRONT_RIGHT_WIDE,
+    Channels::FRONT_LEFT_HIGH,
+    Channels::FRONT_CENTRE_HIGH,
+    Channels::FRONT_RIGHT_HIGH,
+    Channels::LFE2,
+];
+
+/// The speaker of every interleaved channel, in the order the decoder hands out the samples.
+pub fn speaker_layout(mask: Option<Channels>, channels: u32, extension: &str) -> Vec<Channels> {
+    // Vorbis and AAC define a fixed channel order per channel count instead of a speaker mask
+    let fixed = match extension {
+        "ogg" | "vorbis" => vorbis_layout(channels),
+        "mp4" | "m4a" | "m4b" | "m4p" | "m4r" => aac_layout(channels),
+        _ => None,
+    };
+    if let Some(layout) = fixed {
+        return layout;
+    }
+
+    match mask {
+        Some(mask) if mask.count() == channels as usize => WAVE_ORDER.iter().copied().filter(|speaker| mask.contains(*speaker)).collect(),
+        _ => default_layout(channels),
+    }
+}
+
+pub fn default_layout(channels: u32) -> Vec<Channels> {
+    use Channels as C;
+    match channels {
+        1 => vec![C::FRONT_CENTRE],
+        2 => vec![C::FRONT_LEFT, C::FRONT_RIGHT],
+        3 => vec![C::FRONT_LEFT, C::FRONT_RIGHT, C::FRONT_CENTRE],
+        4 => vec![C::FRONT_LEFT, C::FRONT_RIGHT, C::REAR_LEFT, C::REAR_RIGHT],
+        5 => vec![C::FRONT_LEFT, C::FRONT_RIGHT, C::FRONT_CENTRE, C::REAR_LEFT, C::REAR_RIGHT],
+        6 => vec![C::FRONT_LEFT, C::FRONT_RIGHT, C::FRONT_CENTRE, C::LFE1, C::REAR_LEFT, C::REAR_RIGHT],
+        7 => vec![C::FRONT_LEFT, C::FRONT_RIGHT, C::FRONT_CENTRE, C::LFE1, C::REAR_CENTRE, C::SIDE_LEFT, C::SIDE_RIGHT],
+        8 => vec![C::FRONT_LEFT, C::FRONT_RIGHT, C::FRONT_CENTRE, C::LFE1, C::REAR_LEFT, C::REAR_RIGHT, C::SIDE_LEFT, C::SIDE_RIGHT],
+        // there is no convention past 7.1, so at least measure the front pair
+        _ => WAVE_ORDER.iter().copied().take(2).chain(std::iter::repeat(C::LFE2)).take(channels as usize).collect(),
+    }
+}
+
+fn vorbis_layout(channels: u32) -> Option<Vec<Channels>> {
+    use Channels as C;
+    match channels {
+        3 => Some(vec![C::FRONT_LEFT, C::FRONT_CENTRE, C::FRONT_RIGHT]),
+        5 => Some(vec![C::FRONT_LEFT, C::FRONT_CENTRE, C::FRONT_RIGHT, C::REAR_LEFT, C::REAR_RIGHT]),
+        6 => Some(vec![C::FRONT_LEFT, C::FRONT_CENTRE, C::FRONT_RIGHT, C::REAR_LEFT, C::REAR_RIGHT, C::LFE1]),
+        7 => Some(vec![C::FRONT_LEFT, C::FRONT_CENTRE, C::FRONT_RIGHT, C::SIDE_LEFT, C::SIDE_RIGHT, C::REAR_CENTRE, C::LFE1]),
+        8 => Some(vec![C::FRONT_LEFT, C::FRONT_CENTRE, C::FRONT_RIGHT, C::SIDE_LEFT, C::SIDE_RIGHT, C::REAR_LEFT, C::REAR_RIGHT, C::LFE1]),
+        _ => None,
+    }
+}
+
+fn aac_layout(channels: u32) -> Option<Vec<Channels>> {
+    use Channels as C;
+    match channels {
+        3 => Some(vec![C::FRONT_CENTRE, C::FRONT_LEFT, C::FRONT_RIGHT]),
+        4 => Some(vec![C::FRONT_CENTRE, C::FRONT_LEFT, C::FRONT_RIGHT, C::REAR_CENTRE]),
+        5 => Some(vec![C::FRONT_CENTRE, C::FRONT_LEFT, C::FRONT_RIGHT, C::REAR_LEFT, C::REAR_RIGHT]),
+        6 => Some(vec![C::FRONT_CENTRE, C::FRONT_LEFT, C::FRONT_RIGHT, C::REAR_LEFT, C::REAR_RIGHT, C::LFE1]),
+        8 => Some(vec![C::FRONT_CENTRE, C::FRONT_LEFT_CENTRE, C::FRONT_RIGHT_CENTRE, C::FRONT_LEFT, C::FRONT_RIGHT, C::REAR_LEFT, C::REAR_RIGHT, C::LFE1]),
+        _ => None,
+    }
+}
+
+/// Maps the speakers to the BS.1770 channels: LFE is not measured and the surrounds are weighted +1.5 dB.
+pub fn ebur128_channel_map(layout: &[Channels]) -> Vec<Channel> {
+    // in a 7.1 layout the side speakers take the +-90 degree position and the rear ones move behind the listener
+    let has_sides = layout.iter().any(|speaker| *speaker == Channels::SIDE_LEFT || *speaker == Channels::SIDE_RIGHT);
+    let has_rears = layout.iter().any(|speaker| *speaker == Channels::REAR_LEFT || *speaker == Channels::REAR_RIGHT);
+    let seven_one = has_sides && has_rears;
+
+    layout.iter().map(|speaker| match *speaker {
+        Channels::FRONT_LEFT => Channel::Left,
+        Channels::FRONT_RIGHT => Channel::Right,
+        Channels::FRONT_CENTRE => Channel::Center,
+        Channels::LFE1 | Channels::LFE2 => Channel::Unused,
+        Channels::SIDE_LEFT if seven_one => Channel::Mp090,
+        Channels::SIDE_RIGHT if seven_one => Channel::Mm090,
+        Channels::REAR_LEFT if seven_one => Channel::Mp135,
+        Channels::REAR_RIGHT if seven_one => Channel::Mm135,
+        Channels::SIDE_LEFT | Channels::REAR_LEFT => Channel::LeftSurround,
+        Channels::SIDE_RIGHT | Channels::REAR_RIGHT => Channel::RightSurround,
+        Channels::REAR_CENTRE => Channel::Mp180,
+        Channels::FRONT_LEFT_CENTRE => Channel::MpSC,
+        Channels::FRONT_RIGHT_CENTRE => Channel::MmSC,
+        Channels::FRONT_LEFT_WIDE => Channel::Mp060,
+        Channels::FRONT_RIGHT_WIDE => Channel::Mm060,
+        Channels::REAR_LEFT_CENTRE => Channel::Mp135,
+        Channels::REAR_RIGHT_CENTRE => Channel::Mm135,
+        Channels::FRONT_LEFT_HIGH | Channels::TOP_FRONT_LEFT => Channel::Up030,
+        Channels::FRONT_RIGHT_HIGH | Channels::TOP_FRONT_RIGHT => Channel::Um030,
+        Channels::FRONT_CENTRE_HIGH | Channels::TOP_FRONT_CENTRE => Channel::Up000,
+        Channels::TOP_REAR_LEFT => Channel::Up135,
+        Channels::TOP_REAR_RIGHT => Channel::Um135,
+        Channels::TOP_REAR_CENTRE => Channel::Up180,
+        Channels::TOP_CENTRE => Channel::Tp000,
+        _ => Channel::Unused,
+    }).collect()
+}
+
+/// ITU-R BS.775 stereo downmix: centre and surrounds are mixed in at -3 dB, LFE is dropped.
+pub fn downmix_to_stereo(pcm: &[f32], layout: &[Channels]) -> Vec<f32> {
+    let coefficients: Vec<(f32, f32)> = layout.iter().map(|speaker| downmix_coefficients(*speaker)).collect();
+    let mut res = Vec::with_capacity(pcm.len() / layout.len() * 2);
+
+    for frame in pcm.chunks_exact(layout.len()) {
+        let (mut left, mut right) = (0.0, 0.0);
+        for (sample, (to_left, to_right)) in frame.iter().zip(&coefficients) {
+            left += sample * to_left;
+            right += sample * to_right;
+        }
+        res.push(left);
+        res.push(right);
+    }
+
+    res
+}
+
+fn downmix_coefficients(speaker: Channels) -> (f32, f32) {
+    match speaker {
+        Channels::FRONT_LEFT | Channels::FRONT_LEFT_CENTRE | Channels::FRONT_LEFT_WIDE => (1.0, 0.0),
+        Channels::FRONT_RIGHT | Channels::FRONT_RIGHT_CENTRE | Channels::FRONT_RIGHT_WIDE => (0.0, 1.0),
+        Channels::FRONT_CENTRE => (MINUS_3DB, MINUS_3DB),
+        Channels::LFE1 | Channels::LFE2 => (0.0, 0.0),
+        Channels::REAR_LEFT | Channels::SIDE_LEFT | Channels::REAR_LEFT_CENTRE
+        | Channels::FRONT_LEFT_HIGH | Channels::TOP_FRONT_LEFT | Channels::TOP_REAR_LEFT => (MINUS_3DB, 0.0),
+        Channels::REAR_RIGHT | Channels::SIDE_RIGHT | Channels::REAR_RIGHT_CENTRE
+        | Channels::FRONT_RIGHT_HIGH | Channels::TOP_FRONT_RIGHT | Channels::TOP_REAR_RIGHT => (0.0, MINUS_3DB),
+        _ => (0.5, 0.5),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orders_the_speakers_of_the_mask() {
+        let mask = Channels::FRONT_LEFT | Channels::FRONT_RIGHT | Channels::FRONT_CENTRE | Channels::LFE1 | Channels::SIDE_LEFT | Channels::SIDE_RIGHT;
+        assert_eq!(speaker_layout(Some(mask), 6, "flac"), [
+            Channels::FRONT_LEFT, Channels::FRONT_RIGHT, Channels::FRONT_CENTRE, Channels::LFE1, Channels::SIDE_LEFT, Channels::SIDE_RIGHT,
+        ]);
+        // a mask not matching the channel count is ignored
+        assert_eq!(speaker_layout(Some(mask), 2, "flac"), default_layout(2));
+        assert_eq!(speaker_layout(None, 3, "ogg"), [Channels::FRONT_LEFT, Channels::FRONT_CENTRE, Channels::FRONT_RIGHT]);
+        assert_eq!(speaker_layout(Some(mask), 6, "m4a")[0], Channels::FRONT_CENTRE);
+    }
+
+    #[test]
+    fn maps_5_1_to_the_surround_channels() {
+        assert_eq!(ebur128_channel_map(&default_layout(6)), [
+            Channel::Left, Channel::Right, Channel::Center, Channel::Unused, Channel::LeftSurround, Channel::RightSurround,
+        ]);
+    }
+
+    #[test]
+    fn maps_7_1_sides_and_rears_to_their_angles() {
+        assert_eq!(ebur128_channel_map(&default_layout(8)), [
+            Channel::Left, Channel::Right, Channel::Center, Channel::Unused,
+            Channel::Mp135, Channel::Mm135, Channel::Mp090, Channel::Mm090,
+        ]);
+    }
+
+    #[test]
+    fn downmixes_to_stereo() {
+        // one 5.1 frame: L, R, C, LFE, Ls, Rs
+        let frame = [1.0, 0.5, 1.0, 1.0, 1.0, 0.0];
+        let stereo = downmix_to_stereo(&frame, &default_layout(6));
+        assert_eq!(stereo.len(), 2);
+        assert!((stereo[0] - (1.0 + 2.0 * MINUS_3DB)).abs() < 1e-6);
+        assert!((stereo[1] - (0.5 + MINUS_3DB)).abs() < 1e-6);
+
+        // stereo passes through unchanged
+        assert_eq!(downmix_to_stereo(&[0.25, -0.5, 0.1, 0.2], &default_layout(2)), [0.25, -0.5, 0.1, 0.2]);
+    }
+}
@@ -0,0 +1,214 @@
+use std::fmt;
+use std::fmt::{Debug, Formatter};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde_json::{json, Value};
+
+use crate::loudness_types::{Decibel, LoudnessUnitFullScale};
+use crate::path_display::escape_path;
+use crate::replaygain_scanner::ScanResult;
+
+const DEFAULT_TOLERANCE: f64 = 1.0;
+
+/// The loudness a delivery has to meet: an integrated loudness target with a tolerance and optional ceilings
+/// for the true peak and the loudness range.
+#[derive(Clone)]
+pub struct LoudnessSpec {
+    pub name: String,
+    pub target: LoudnessUnitFullScale,
+    pub tolerance: Decibel,
+    pub max_true_peak: Option<Decibel>,
+    pub max_range: Option<Decibel>,
+}
+
+impl LoudnessSpec {
+    fn builtin(name: &str, target: f64, tolerance: f64, max_true_peak: f64) -> Self {
+        LoudnessSpec {
+            name: name.to_string(),
+            target: LoudnessUnitFullScale::new(target),
+            tolerance: Decibel::new(tolerance),
+            max_true_peak: Some(Decibel::new(max_true_peak)),
+            max_range: None,
+        }
+    }
+
+    /// Parses a user defined spec like `target=-16,tolerance=1,true-peak=-1,range=12,name=podcast`.
+    fn custom(s: &str) -> Result<Self, String> {
+        let mut spec = LoudnessSpec {
+            name: "custom".to_string(),
+            target: LoudnessUnitFullScale::new(f64::NAN),
+            tolerance: Decibel::new(DEFAULT_TOLERANCE),
+            max_true_peak: None,
+            max_range: None,
+        };
+        for field in s.split(',') {
+            let (key, value) = field.split_once('=').ok_or_else(|| format!("Cannot parse {} into a loudness spec: expected key=value.", s))?;
+            if key.trim() == "name" {
+                spec.name = value.trim().to_string();
+                continue;
+            }
+            let number: f64 = value.trim().parse().map_err(|_| format!("Cannot parse {} into a loudness spec: {} is not a number.", s, value))?;
+            match key.trim() {
+                "target" => spec.target = LoudnessUnitFullScale::new(number),
+                "tolerance" => spec.tolerance = Decibel::new(number),
+                "true-peak" => spec.max_true_peak = Some(Decibel::new(number)),
+                "range" => spec.max_range = Some(Decibel::new(number)),
+                _ => return Err(format!("Cannot parse {} into a loudness spec: unknown key {}.", s, key)),
+            }
+        }
+        if spec.target.as_f64().is_nan() {
+            return Err(format!("Cannot parse {} into a loudness spec: the target is missing.", s));
+        }
+
+        Ok(spec)
+    }
+}
+
+impl FromStr for LoudnessSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ebu-r128" => Ok(LoudnessSpec::builtin(s, -23.0, 0.5, -1.0)),
+            "atsc-a85" => Ok(LoudnessSpec::builtin(s, -24.0, 2.0, -2.0)),
+            "spotify" => Ok(LoudnessSpec::builtin(s, -14.0, DEFAULT_TOLERANCE, -1.0)),
+            "apple" => Ok(LoudnessSpec::builtin(s, -16.0, DEFAULT_TOLERANCE, -1.0)),
+            "youtube" => Ok(LoudnessSpec::builtin(s, -14.0, DEFAULT_TOLERANCE, -1.0)),
+            _ if s.contains('=') => LoudnessSpec::custom(s),
+            _ => Err(format!("Cannot parse {} into a loudness spec.", s)),
+        }
+    }
+}
+
+impl fmt::Display for LoudnessSpec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+impl Debug for LoudnessSpec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+/// How a scanned file measures up against a spec.
+pub struct Compliance {
+    pub filepath: PathBuf,
+    pub failures: Vec<String>,
+    report: Value,
+}
+
+impl Compliance {
+    pub fn passes(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+pub fn check_compliance(filepath: &Path, scan: &ScanResult, spec: &LoudnessSpec) -> Compliance {
+    let integrated = scan.integrated_loudness;
+    let true_peak = scan.true_peak.as_dB();
+    let range = scan.loudness_range;
+    let mut failures = Vec::new();
+
+    let deviation = (integrated - spec.target).as_f64().abs();
+    // unmeasurable files (NaN or -inf LUFS) fail too
+    if !deviation.is_finite() || deviation > spec.tolerance.as_f64() {
+        failures.push(format!("integrated loudness {} is not within {:.2} LU of {}", integrated, spec.tolerance.as_f64(), spec.target));
+    }
+    if let Some(max_true_peak) = spec.max_true_peak {
+        if true_peak.as_f64() > max_true_peak.as_f64() {
+            failures.push(format!("true peak {:.2} dBTP is above {:.2} dBTP", true_peak.as_f64(), max_true_peak.as_f64()));
+        }
+    }
+    if let Some(max_range) = spec.max_range {
+        if range.as_f64() > max_range.as_f64() {
+            failures.push(format!("loudness range {:.2} LU is above {:.2} LU", range.as_f64(), max_range.as_f64()));
+        }
+    }
+
+    // non-finite values have no JSON representation and end up as null
+    let report = json!({
+        "file": escape_path(filepath),
+        "spec": spec.name,
+        "pass": failures.is_empty(),
+        "integrated_loudness": integrated.as_f64(),
+        "true_peak": true_peak.as_f64(),
+        "loudness_range": range.as_f64(),
+        "failures": failures,
+    });
+    Compliance { filepath: filepath.to_path_buf(), failures, report }
+}
+
+pub fn format_compliance_report(results: &[Compliance], spec: &LoudnessSpec) -> String {
+    json!({
+        "spec": {
+            "name": spec.name,
+            "target": spec.target.as_f64(),
+            "tolerance": spec.tolerance.as_f64(),
+            "max_true_peak": spec.max_true_peak.map(|peak| peak.as_f64()),
+            "max_range": spec.max_range.map(|range| range.as_f64()),
+        },
+        "pass": results.iter().all(Compliance::passes),
+        "files": results.iter().map(|result| result.report.clone()).collect::<Vec<_>>(),
+    }).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::replaygain_scanner::{ChannelPeak, LoudnessPoint};
+
+    use super::*;
+
+    fn spec(s: &str) -> LoudnessSpec {
+        s.parse().expect("To be a loudness spec")
+    }
+
+    fn scan(integrated_loudness: f64, range: f64, true_peak: f64) -> ScanResult {
+        let timeline = (1..=30).map(|step| LoudnessPoint::new(step as f64 / 10.0, integrated_loudness, integrated_loudness)).collect();
+        ScanResult::new(integrated_loudness, range, integrated_loudness - 10.0, vec![ChannelPeak::new(true_peak, true_peak)], timeline)
+    }
+
+    #[test]
+    fn parses_the_builtin_specs() {
+        let ebu = spec("ebu-r128");
+        assert_eq!((ebu.target.as_f64(), ebu.tolerance.as_f64()), (-23.0, 0.5));
+        assert_eq!(ebu.max_true_peak.map(|peak| peak.as_f64()), Some(-1.0));
+        assert!(ebu.max_range.is_none());
+        assert_eq!(spec("spotify").target.as_f64(), -14.0);
+        assert!("netflix".parse::<LoudnessSpec>().is_err());
+    }
+
+    #[test]
+    fn parses_custom_specs() {
+        let custom = spec("target=-16, tolerance=0.5,true-peak=-1.5,range=12,name=podcast");
+        assert_eq!(custom.name, "podcast");
+        assert_eq!((custom.target.as_f64(), custom.tolerance.as_f64()), (-16.0, 0.5));
+        assert_eq!(custom.max_true_peak.map(|peak| peak.as_f64()), Some(-1.5));
+        assert_eq!(custom.max_range.map(|range| range.as_f64()), Some(12.0));
+
+        let minimal = spec("target=-18");
+        assert_eq!((minimal.name.as_str(), minimal.tolerance.as_f64()), ("custom", DEFAULT_TOLERANCE));
+        assert!(minimal.max_true_peak.is_none());
+    }
+
+    #[test]
+    fn rejects_malformed_custom_specs() {
+        let error = |s: &str| s.parse::<LoudnessSpec>().expect_err("To be malformed");
+        assert!(error("tolerance=1").ends_with("the target is missing."));
+        assert!(error("target=loud").ends_with("loud is not a number."));
+        assert!(error("target=-16,peak=-1").ends_with("unknown key peak."));
+        assert!(error("target=-16,range").ends_with("expected key=value."));
+    }
+
+    #[test]
+    fn checks_the_scan_against_the_spec() {
+        let spec = spec("target=-16,tolerance=1,true-peak=-1,range=10");
+        assert!(check_compliance(Path::new("a.flac"), &scan(-16.5, 5.0, 0.5), &spec).passes());
+
+        let failures = check_compliance(Path::new("a.flac"), &scan(-18.0, 12.0, 1.0), &spec).failures;
+        assert_eq!(failures.len(), 3);
+        assert!(!check_compliance(Path::new("a.flac"), &scan(f64::NEG_INFINITY, 0.0, 0.0), &spec).passes());
+    }
+}
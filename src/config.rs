@@ -0,0 +1,217 @@
+use std::env;
+use std::error::Error;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+use clap::{App, Arg, ArgMatches, ErrorKind, FromArgMatches, IntoApp};
+use toml::Value;
+
+use crate::args::Args;
+use crate::path_display::escape_path;
+
+const CONFIG_FILE: &str = "loudgain-rust/config.toml";
+/// Per-library config, searched for in the scanned paths and their parents.
+const LIBRARY_CONFIG_FILE: &str = ".loudgain-rust.toml";
+const PROFILE_OPTION: &str = "profile";
+const RESET_PREFIX: &str = "no-";
+
+/// An option name and its arguments.
+type ConfigOption = (String, Vec<OsString>);
+
+/// The command line merged with the options of the config files, the command line wins.
+///
+/// A config file holds long option names with their values, the options for every run at the top level and
+/// named profiles in `[profiles.<name>]` tables, picked with `--profile`. The library config replaces the options
+/// of the user config, and the profile those of both:
+///
+/// ```toml
+/// noclip = true
+/// tagmode = "e"
+///
+/// [profiles.podcast]
+/// reference = -16
+/// keep-tag = ["TITLE", "ARTIST"]
+/// ```
+///
+/// An option given on the command line replaces its config values, and so does an option conflicting with it.
+/// `--no-<option>` drops the config values of an option, which turns off a flag set in a config file.
+pub fn args_with_config() -> Args {
+    let app = with_resets(Args::into_app());
+    let mut cli = env::args_os();
+    let program = cli.next().unwrap_or_default();
+    let cli: Vec<OsString> = cli.collect();
+    let command_line = |config: &[OsString]| -> Vec<OsString> { std::iter::once(&program).chain(config).chain(&cli).cloned().collect() };
+
+    let cli_matches = app.clone().get_matches_from(command_line(&[]));
+    let inputs: Vec<PathBuf> = cli_matches.values_of_os("files").map(|files| files.map(PathBuf::from).collect()).unwrap_or_default();
+    let profile = option_name(&app, PROFILE_OPTION).and_then(|name| cli_matches.value_of(name));
+    let options = config_options(&config_files(&inputs), profile).unwrap_or_else(|err| {
+        eprintln!("Cannot read the config: {}", err);
+        exit(1);
+    });
+
+    let config: Vec<OsString> = options.into_iter()
+        .filter(|(key, _)| !overridden(&app, &cli_matches, key))
+        .filter(|(_, args)| !matches!(app.clone().try_get_matches_from(command_line(args)), Err(err) if err.kind == ErrorKind::ArgumentConflict))
+        .flat_map(|(_, args)| args)
+        .collect();
+    let matches = app.get_matches_from(command_line(&config));
+    Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit())
+}
+
+/// The user config followed by the library configs of the scanned paths, which override it.
+pub fn config_files(inputs: &[PathBuf]) -> Vec<PathBuf> {
+    let user_config_dir = env::var_os("XDG_CONFIG_HOME").map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+
+    // stdin and a run without paths have no library of their own, the one of the working directory applies
+    let mut roots: Vec<PathBuf> = inputs.iter().filter_map(|input| fs::canonicalize(input).ok()).collect();
+    if roots.is_empty() {
+        roots.extend(env::current_dir());
+    }
+    let mut library_configs: Vec<PathBuf> = Vec::new();
+    for root in roots {
+        let found = root.ancestors().map(|dir| dir.join(LIBRARY_CONFIG_FILE)).find(|file| file.is_file());
+        if let Some(file) = found.filter(|file| !library_configs.contains(file)) {
+            library_configs.push(file);
+        }
+    }
+
+    user_config_dir.map(|dir| dir.join(CONFIG_FILE)).into_iter()
+        .chain(library_configs)
+        .filter(|file| file.is_file())
+        .collect()
+}
+
+// every option gets a hidden --no-<option>, which only matters for the config values
+fn with_resets(app: App<'static>) -> App<'static> {
+    let longs: Vec<&'static str> = app.get_arguments().filter_map(|arg| arg.get_long()).collect();
+    longs.into_iter().fold(app, |app, long| {
+        // clap keeps the names for the whole run
+        let reset: &'static str = Box::leak(format!("{}{}", RESET_PREFIX, long).into_boxed_str());
+        app.arg(Arg::new(reset).long(reset).hide(true))
+    })
+}
+
+fn option_name(app: &App<'static>, long: &str) -> Option<&'static str> {
+    app.get_arguments().find(|arg| arg.get_long() == Some(long)).map(|arg| arg.get_name())
+}
+
+fn overridden(app: &App<'static>, cli: &ArgMatches, key: &str) -> bool {
+    let set = option_name(app, key).is_some_and(|name| cli.occurrences_of(name) > 0);
+    let reset = option_name(app, &format!("{}{}", RESET_PREFIX, key)).is_some_and(|name| cli.is_present(name));
+    set || reset
+}
+
+/// The arguments of every option in the config files, the later files and the profile replacing the values of
+/// the earlier ones.
+fn config_options(files: &[PathBuf], profile: Option<&str>) -> Result<Vec<ConfigOption>, Box<dyn Error>> {
+    let configs = files.iter().map(|file| {
+        let contents = fs::read_to_string(file)?;
+        contents.parse::<Value>().map_err(|err| format!("{}: {}", escape_path(file), err).into())
+    }).collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+
+    let mut tables = Vec::new();
+    for config in &configs {
+        let table = config.as_table().ok_or("The config is not a table")?;
+        tables.push(table.iter().filter(|(key, _)| *key != "profiles").collect::<Vec<_>>());
+    }
+    if let Some(profile) = profile {
+        let profiles: Vec<_> = configs.iter().filter_map(|config| config.get("profiles")?.get(profile)?.as_table()).collect();
+        if profiles.is_empty() {
+            return Err(format!("There is no profile named {}", profile).into());
+        }
+        tables.extend(profiles.into_iter().map(|table| table.iter().collect()));
+    }
+
+    let mut res: Vec<ConfigOption> = Vec::new();
+    for (key, value) in tables.into_iter().flatten() {
+        let mut args = Vec::new();
+        push_option(&mut args, key, value)?;
+        res.retain(|(other, _)| other != key);
+        res.push((key.clone(), args.into_iter().map(OsString::from).collect()));
+    }
+    Ok(res)
+}
+
+fn push_option(args: &mut Vec<String>, key: &str, value: &Value) -> Result<(), Box<dyn Error>> {
+    match value {
+        // a flag set to false is the same as leaving it out
+        Value::Boolean(set) => if *set { args.push(format!("--{}", key)) },
+        // the values are attached with "=" so that negative numbers are not taken for options
+        Value::String(value) => args.push(format!("--{}={}", key, value)),
+        Value::Integer(value) => args.push(format!("--{}={}", key, value)),
+        Value::Float(value) => args.push(format!("--{}={}", key, value)),
+        Value::Array(values) => for value in values {
+            push_option(args, key, value)?;
+        },
+        _ => return Err(format!("The value of {} cannot be turned into an option", key).into()),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(configs: &[&str], profile: Option<&str>) -> Result<Vec<ConfigOption>, Box<dyn Error>> {
+        let directory = tempfile::tempdir()?;
+        let files: Vec<PathBuf> = configs.iter().enumerate().map(|(i, contents)| {
+            let file = directory.path().join(format!("{}.toml", i));
+            fs::write(&file, contents).expect("To write the config");
+            file
+        }).collect();
+        config_options(&files, profile)
+    }
+
+    fn option(key: &str, args: &[&str]) -> ConfigOption {
+        (key.to_string(), args.iter().map(OsString::from).collect())
+    }
+
+    #[test]
+    fn turns_values_into_options() {
+        let options = options(&["noclip = true\nstriptags = false\nreference = -16\npregain = 1.5\ntagmode = \"e\"\nkeep-tag = [\"TITLE\", \"ARTIST\"]"], None)
+            .expect("To read the config");
+        assert_eq!(options, [
+            option("keep-tag", &["--keep-tag=TITLE", "--keep-tag=ARTIST"]),
+            option("noclip", &["--noclip"]),
+            option("pregain", &["--pregain=1.5"]),
+            option("reference", &["--reference=-16"]),
+            option("striptags", &[]),
+            option("tagmode", &["--tagmode=e"]),
+        ]);
+        assert!(push_option(&mut Vec::new(), "tagmode", &Value::Table(Default::default())).is_err());
+    }
+
+    #[test]
+    fn later_configs_and_the_profile_win() {
+        let user = "reference = -18\nnoclip = true\n[profiles.podcast]\nreference = -16";
+        let library = "reference = -20\n[profiles.podcast]\ntagmode = \"s\"";
+        assert_eq!(options(&[user, library], None).expect("To read the config"), [
+            option("noclip", &["--noclip"]),
+            option("reference", &["--reference=-20"]),
+        ]);
+        assert_eq!(options(&[user, library], Some("podcast")).expect("To read the config"), [
+            option("noclip", &["--noclip"]),
+            option("reference", &["--reference=-16"]),
+            option("tagmode", &["--tagmode=s"]),
+        ]);
+        assert!(options(&[user], Some("missing")).is_err());
+        assert!(options(&["reference = "], None).is_err());
+    }
+
+    #[test]
+    fn finds_the_library_config_above_the_paths() {
+        let directory = tempfile::tempdir().expect("To create a temp dir");
+        let root = fs::canonicalize(directory.path()).expect("To find the temp dir");
+        let album = root.join("artist/album");
+        fs::create_dir_all(&album).expect("To create the album directory");
+        fs::write(root.join(LIBRARY_CONFIG_FILE), "noclip = true").expect("To write the config");
+
+        let files = config_files(&[album.join("a.flac"), album]);
+        assert_eq!(files.iter().filter(|file| file.starts_with(&root)).collect::<Vec<_>>(), [&root.join(LIBRARY_CONFIG_FILE)]);
+    }
+}
@@ -0,0 +1,322 @@
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::args::{ARGS, ScanMode, SilenceHandling};
+use crate::decode_audio::{decode_file, DecodedFile};
+use crate::path_display::escape_path;
+use crate::playlist::{cue_entry, entry_path};
+use crate::replaygain_scanner::{get_album_gain, get_track_gain, scan_file, ScanError, ScanResult};
+use crate::replaygain_scanner::TrackGain;
+use crate::tags::{get_file_extension, replace_file_contents, save_album_tags, track_values};
+
+// CUE sheets count time in CD frames
+const FRAMES_PER_SECOND: u64 = 75;
+const REM_PREFIX: &[u8] = b"REM REPLAYGAIN_";
+const TRACK_INDENT: &str = "    ";
+
+/// A CUE sheet splitting a single audio file into several tracks.
+pub struct CueSheet {
+    pub path: PathBuf,
+    pub audio: PathBuf,
+    pub tracks: Vec<CueTrack>,
+}
+
+impl CueSheet {
+    /// Whether the scan result belongs to one of the tracks of the sheet.
+    pub fn contains(&self, track: &Path) -> bool {
+        self.tracks.iter().any(|other| self.track_label(other.number) == track)
+    }
+
+    /// A path naming the track like a file of its own, for the outputs written per track.
+    pub fn track_file(&self, number: u32) -> PathBuf {
+        let stem = self.audio.file_stem().unwrap_or_default().to_string_lossy();
+        self.audio.with_file_name(format!("{} {:02}.{}", stem, number, get_file_extension(&self.audio)))
+    }
+
+    // reports and JSON output show the track as <audio file>#<track number>
+    fn track_label(&self, number: u32) -> PathBuf {
+        let mut label = OsString::from(&self.audio);
+        label.push(format!("#{:02}", number));
+        PathBuf::from(label)
+    }
+}
+
+pub struct CueTrack {
+    pub number: u32,
+    /// Where the track starts (INDEX 01), in CD frames. It ends where the next one starts, so a pregap
+    /// belongs to the track before it.
+    pub start: u64,
+}
+
+pub fn is_cue_sheet(path: &Path) -> bool {
+    get_file_extension(path).eq_ignore_ascii_case("cue")
+}
+
+/// Finds the CUE sheets splitting one of the songs into tracks, among the arguments and, with `--cue-sheets`,
+/// next to the songs. A sheet that cannot be read is reported and the files it lists are measured as a whole.
+pub fn find_cue_sheets(arguments: &[PathBuf], songs: &[PathBuf]) -> Vec<CueSheet> {
+    let directories: BTreeSet<&Path> = if ARGS.cue_sheets {
+        songs.iter().filter_map(|song| song.parent()).collect()
+    } else { BTreeSet::new() };
+    let neighbours = directories.into_iter()
+        .filter_map(|directory| fs::read_dir(directory).ok())
+        .flat_map(|entries| entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()));
+    let mut candidates: Vec<PathBuf> = arguments.iter().cloned().chain(neighbours)
+        .filter(|path| is_cue_sheet(path))
+        .filter_map(|path| fs::canonicalize(path).ok())
+        .collect();
+    candidates.sort();
+    candidates.dedup();
+
+    let mut res: Vec<CueSheet> = Vec::new();
+    for path in candidates {
+        match read_cue_sheet(&path) {
+            // an audio file split by two sheets is measured once
+            Ok(Some(sheet)) if songs.contains(&sheet.audio) && !res.iter().any(|other| other.audio == sheet.audio) => res.push(sheet),
+            Ok(_) => {}
+            Err(err) => eprintln!("Cannot read the CUE sheet {}, the files it lists are measured as a whole: {}", escape_path(&path), err),
+        }
+    }
+    res
+}
+
+/// Reads the CUE sheet if it describes several tracks of a single file, which is then measured track by track.
+/// Any other CUE sheet is a plain list of files.
+pub fn read_cue_sheet(path: &Path) -> Result<Option<CueSheet>, Box<dyn Error>> {
+    let contents = fs::read(path)?;
+    let (files, tracks) = parse_cue_sheet(&contents)?;
+    if files.len() != 1 || tracks.len() < 2 {
+        return Ok(None);
+    }
+
+    let directory = path.parent().unwrap_or_else(|| Path::new(""));
+    let audio = directory.join(&files[0]);
+    let audio = fs::canonicalize(&audio).map_err(|err| format!("Cannot find {}: {}", escape_path(&audio), err))?;
+    Ok(Some(CueSheet { path: path.to_path_buf(), audio, tracks }))
+}
+
+fn parse_cue_sheet(contents: &[u8]) -> Result<(Vec<PathBuf>, Vec<CueTrack>), String> {
+    let mut files = Vec::new();
+    let mut tracks: Vec<(u32, Option<u64>)> = Vec::new();
+    for (number, line) in contents.split(|byte| *byte == b'\n').map(|line| line.trim_ascii()).enumerate() {
+        let error = || format!("line {} is malformed: {}", number + 1, String::from_utf8_lossy(line));
+        let mut words = line.split(|byte| *byte == b' ').filter(|word| !word.is_empty());
+        match words.next() {
+            // URLs are skipped like in playlists
+            Some(b"FILE") => files.extend(entry_path(cue_entry(line).ok_or_else(error)?)),
+            Some(b"TRACK") => tracks.push((parse_word(words.next()).ok_or_else(error)?, None)),
+            Some(b"INDEX") if words.next() == Some(b"01") => {
+                let start = words.next().and_then(cue_time).ok_or_else(error)?;
+                tracks.last_mut().ok_or_else(error)?.1 = Some(start);
+            }
+            _ => {}
+        }
+    }
+
+    let tracks = tracks.into_iter().map(|(number, start)| match start {
+        Some(start) => Ok(CueTrack { number, start }),
+        None => Err(format!("TRACK {:02} has no INDEX 01", number)),
+    }).collect::<Result<_, _>>()?;
+    Ok((files, tracks))
+}
+
+fn parse_word<T: FromStr>(word: Option<&[u8]>) -> Option<T> {
+    std::str::from_utf8(word?).ok()?.parse().ok()
+}
+
+// mm:ss:ff
+fn cue_time(time: &[u8]) -> Option<u64> {
+    let parts: Vec<u64> = std::str::from_utf8(time).ok()?.split(':').map(|part| part.parse().ok()).collect::<Option<_>>()?;
+    match parts[..] {
+        [minutes, seconds, frames] => Some((minutes * 60 + seconds) * FRAMES_PER_SECOND + frames),
+        _ => None,
+    }
+}
+
+/// Decodes the file once and measures every track on its own slice of the samples. The album is measured
+/// from the tracks together, like the files of an album.
+pub fn scan_cue_sheet(sheet: &CueSheet) -> Result<Vec<(TrackGain, ScanResult)>, Box<dyn Error>> {
+    let decoded = decode_file(&sheet.audio)?;
+    let channels = decoded.channels as usize;
+    let sample = |frames: u64| (frames * decoded.rate as u64 / FRAMES_PER_SECOND) as usize * channels;
+
+    let scans = sheet.tracks.iter().enumerate().map(|(i, track)| {
+        let start = sample(track.start).min(decoded.pcm.len());
+        let end = sheet.tracks.get(i + 1).map_or(decoded.pcm.len(), |next| sample(next.start).min(decoded.pcm.len()));
+        let pcm = decoded.pcm[start..end.max(start)].to_vec();
+        scan_file(DecodedFile::new(pcm, decoded.channels, decoded.rate).with_layout(decoded.layout.clone()), &ARGS.scan_options())
+    }).collect::<Result<Vec<_>, ScanError>>()?;
+
+    let album = get_album_gain(scans.iter());
+    Ok(sheet.tracks.iter().zip(scans).map(|(track, scan)| {
+        (TrackGain { album: Some(album), ..get_track_gain(sheet.track_label(track.number), &scan) }, scan)
+    }).collect())
+}
+
+/// Writes the track values as REM lines of the CUE sheet and the album values to the audio file, or removes both.
+pub fn save_cue_tags(sheet: &CueSheet, tracks: &[TrackGain]) -> Result<(), Box<dyn Error>> {
+    let album = tracks.iter().find_map(|track| track.album).ok_or("The CUE sheet has no measured tracks")?;
+    let extension = get_file_extension(&sheet.path);
+    let values: Vec<Vec<(String, String)>> = match ARGS.scan_mode {
+        ScanMode::DontWriteTags => return Ok(()),
+        ScanMode::DeleteTags => Vec::new(),
+        _ => tracks.iter().map(|track| rem_values(track, extension)).collect(),
+    };
+
+    // the audio file goes first, so that the CUE sheet is left alone if it cannot be written
+    save_album_tags(&sheet.audio, &album)?;
+
+    let contents = fs::read(&sheet.path)?;
+    replace_file_contents(&sheet.path, &rewrite_rem_lines(&contents, &values))
+}
+
+/// The track values written like to a file of its own, none for a track that is not tagged.
+fn rem_values(track: &TrackGain, extension: &str) -> Vec<(String, String)> {
+    if !track.status.is_measured() && matches!(ARGS.silence, SilenceHandling::Skip) {
+        if !ARGS.quiet {
+            println!("Not tagging {} as it is {}", escape_path(&track.filepath), track.status);
+        }
+        return Vec::new();
+    }
+    track_values(track, extension)
+}
+
+/// Drops the ReplayGain REM lines of the CUE sheet and adds `values` after the TRACK lines, one list per track.
+fn rewrite_rem_lines(contents: &[u8], values: &[Vec<(String, String)>]) -> Vec<u8> {
+    let newline: &[u8] = if contents.windows(2).any(|window| window == b"\r\n") { b"\r\n" } else { b"\n" };
+    let mut res = Vec::with_capacity(contents.len());
+    let mut track = 0;
+    // the last line has no newline after it if the file does not end with one
+    for line in contents.split_inclusive(|byte| *byte == b'\n') {
+        let trimmed = line.trim_ascii();
+        if trimmed.len() >= REM_PREFIX.len() && trimmed[..REM_PREFIX.len()].eq_ignore_ascii_case(REM_PREFIX) {
+            continue;
+        }
+        res.extend(line);
+        if trimmed.starts_with(b"TRACK ") {
+            if !line.ends_with(b"\n") {
+                res.extend(newline);
+            }
+            for (key, value) in values.get(track).into_iter().flatten() {
+                res.extend(format!("{}REM {} {}", TRACK_INDENT, key, value).as_bytes());
+                res.extend(newline);
+            }
+            track += 1;
+        }
+    }
+    res
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::replaygain_scanner::{ChannelPeak, LoudnessPoint};
+
+    use super::*;
+
+    fn values(gains: &[&str]) -> Vec<Vec<(String, String)>> {
+        gains.iter().map(|gain| vec![
+            ("REPLAYGAIN_TRACK_GAIN".to_string(), gain.to_string()),
+            ("REPLAYGAIN_TRACK_PEAK".to_string(), "0.500000".to_string()),
+        ]).collect()
+    }
+
+    fn track(integrated_loudness: f64, seconds: usize) -> TrackGain {
+        let timeline = (1..=seconds * 10).map(|step| LoudnessPoint::new(step as f64 / 10.0, integrated_loudness, integrated_loudness)).collect();
+        let scan = ScanResult::new(integrated_loudness, 5.0, integrated_loudness - 10.0, vec![ChannelPeak::new(0.5, 0.5)], timeline);
+        TrackGain { album: Some(get_album_gain([&scan].into_iter())), ..get_track_gain(PathBuf::from("album.flac#01"), &scan) }
+    }
+
+    #[test]
+    fn parses_the_tracks() {
+        let contents = b"REM GENRE Rock\r\nFILE \"album.flac\" WAVE\r\n  TRACK 01 AUDIO\r\n    INDEX 01 00:00:00\r\n  TRACK 02 AUDIO\r\n    INDEX 00 03:59:70\r\n    INDEX 01 04:00:01\r\nFILE http://example.com/a.flac WAVE";
+        let (files, tracks) = parse_cue_sheet(contents).expect("To be a CUE sheet");
+        assert_eq!(files, [PathBuf::from("album.flac")]);
+        let tracks: Vec<(u32, u64)> = tracks.iter().map(|track| (track.number, track.start)).collect();
+        assert_eq!(tracks, [(1, 0), (2, 240 * FRAMES_PER_SECOND + 1)]);
+    }
+
+    #[test]
+    fn reports_malformed_lines() {
+        let error = |contents: &[u8]| parse_cue_sheet(contents).err().expect("To be malformed");
+        assert_eq!(error(b"FILE a.flac WAVE\nTRACK one AUDIO\n"), "line 2 is malformed: TRACK one AUDIO");
+        assert_eq!(error(b"TRACK 01 AUDIO\n  INDEX 01 00:00\n"), "line 2 is malformed: INDEX 01 00:00");
+        assert_eq!(error(b"INDEX 01 00:00:00\n"), "line 1 is malformed: INDEX 01 00:00:00");
+        assert_eq!(error(b"TRACK 01 AUDIO\nTRACK 02 AUDIO\nINDEX 01 00:00:00\n"), "TRACK 01 has no INDEX 01");
+    }
+
+    #[test]
+    fn reads_only_sheets_splitting_one_file() {
+        let directory = tempfile::tempdir().expect("To create a temp dir");
+        let root = fs::canonicalize(directory.path()).expect("To find the temp dir");
+        fs::write(root.join("album.flac"), b"").expect("To write the audio file");
+        let write = |name: &str, contents: &[u8]| {
+            fs::write(root.join(name), contents).expect("To write the CUE sheet");
+            root.join(name)
+        };
+
+        let split = write("split.cue", b"FILE album.flac WAVE\nTRACK 01 AUDIO\nINDEX 01 00:00:00\nTRACK 02 AUDIO\nINDEX 01 01:00:00\n");
+        let sheet = read_cue_sheet(&split).expect("To read the CUE sheet").expect("To split the file");
+        assert_eq!(sheet.audio, root.join("album.flac"));
+        assert!(sheet.contains(&root.join("album.flac#02")));
+        assert_eq!(sheet.track_file(2), root.join("album 02.flac"));
+
+        let single = write("single.cue", b"FILE album.flac WAVE\nTRACK 01 AUDIO\nINDEX 01 00:00:00\n");
+        assert!(read_cue_sheet(&single).expect("To read the CUE sheet").is_none());
+        let missing = write("missing.cue", b"FILE missing.flac WAVE\nTRACK 01 AUDIO\nINDEX 01 00:00:00\nTRACK 02 AUDIO\nINDEX 01 01:00:00\n");
+        assert!(read_cue_sheet(&missing).is_err());
+    }
+
+    #[test]
+    fn rewrites_the_rem_lines_with_crlf_and_no_trailing_newline() {
+        let contents = b"REM GENRE Rock\r\nREM REPLAYGAIN_ALBUM_GAIN -1.00 dB\r\nFILE \"album.flac\" WAVE\r\n  TRACK 01 AUDIO\r\n    rem replaygain_track_gain -1.00 dB\r\n    INDEX 01 00:00:00\r\n  TRACK 02 AUDIO";
+        let expected = b"REM GENRE Rock\r\nFILE \"album.flac\" WAVE\r\n  TRACK 01 AUDIO\r\n    REM REPLAYGAIN_TRACK_GAIN -6.00 dB\r\n    REM REPLAYGAIN_TRACK_PEAK 0.500000\r\n    INDEX 01 00:00:00\r\n  TRACK 02 AUDIO\r\n    REM REPLAYGAIN_TRACK_GAIN -7.00 dB\r\n    REM REPLAYGAIN_TRACK_PEAK 0.500000\r\n";
+        let rewritten = rewrite_rem_lines(contents, &values(&["-6.00 dB", "-7.00 dB"]));
+        assert_eq!(String::from_utf8_lossy(&rewritten), String::from_utf8_lossy(expected));
+
+        // writing again replaces the lines instead of adding more
+        assert_eq!(rewrite_rem_lines(&rewritten, &values(&["-6.00 dB", "-7.00 dB"])), rewritten);
+    }
+
+    #[test]
+    fn writes_the_track_values_only() {
+        let values = rem_values(&track(-20.0, 10), "cue");
+        assert_eq!(values, [
+            ("REPLAYGAIN_TRACK_GAIN".to_string(), "2.00 dB".to_string()),
+            ("REPLAYGAIN_TRACK_PEAK".to_string(), "0.5000000".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn skips_unmeasured_tracks() {
+        assert!(rem_values(&track(f64::NEG_INFINITY, 10), "cue").is_empty());
+        assert!(rem_values(&track(-20.0, 0), "cue").is_empty());
+    }
+
+    #[test]
+    fn finds_only_the_given_sheets() {
+        let directory = tempfile::tempdir().expect("To create a temp dir");
+        let root = fs::canonicalize(directory.path()).expect("To find the temp dir");
+        let song = root.join("album.flac");
+        fs::write(&song, b"").expect("To write the audio file");
+        let sheet = root.join("album.cue");
+        fs::write(&sheet, b"FILE album.flac WAVE\nTRACK 01 AUDIO\nINDEX 01 00:00:00\nTRACK 02 AUDIO\nINDEX 01 01:00:00\n").expect("To write the CUE sheet");
+
+        // sheets next to the songs are only looked for with --cue-sheets
+        let songs = [song];
+        assert!(find_cue_sheets(&songs, &songs).is_empty());
+        let found = find_cue_sheets(&[sheet.clone(), songs[0].clone()], &songs);
+        assert_eq!(found.iter().map(|sheet| &sheet.path).collect::<Vec<_>>(), [&sheet]);
+    }
+
+    #[test]
+    fn removes_the_rem_lines() {
+        let contents = b"FILE album.flac WAVE\n  TRACK 01 AUDIO\n    REM REPLAYGAIN_TRACK_GAIN -6.00 dB\n    INDEX 01 00:00:00\n  TRACK 02 AUDIO\n    INDEX 01 01:00:00\n    REM REPLAYGAIN_TRACK_PEAK 0.500000";
+        let expected = b"FILE album.flac WAVE\n  TRACK 01 AUDIO\n    INDEX 01 00:00:00\n  TRACK 02 AUDIO\n    INDEX 01 01:00:00\n";
+        assert_eq!(String::from_utf8_lossy(&rewrite_rem_lines(contents, &[])), String::from_utf8_lossy(expected));
+    }
+}
@@ -1,40 +1,131 @@
 use std::{fmt, fs};
 use std::error::Error;
 use std::fs::File;
+use std::io::{Cursor, Read, Seek};
 use std::path::Path;
+use std::sync::Arc;
 
 use rodio::decoder::Decoder;
 use rodio::Source;
+use symphonia::core::audio::Channels;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::{MediaSource, MediaSourceStream};
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+use symphonia::default::get_probe;
+
+use crate::args::RawFormat;
+use crate::channel_layout::{default_layout, speaker_layout};
 
 pub struct DecodedFile {
     pub pcm: Vec<i16>,
     pub channels: u32,
     pub rate: u32,
+    /// The speaker of every interleaved channel.
+    pub layout: Vec<Channels>,
 }
 
 impl DecodedFile {
     pub fn new(pcm: Vec<i16>, channels: u32, rate: u32) -> Self {
-        Self { pcm, channels, rate }
+        Self { pcm, channels, rate, layout: default_layout(channels) }
+    }
+
+    pub fn with_layout(self, layout: Vec<Channels>) -> Self {
+        Self { layout, ..self }
     }
 }
 
 impl fmt::Debug for DecodedFile {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "pcm.len() = {}, channels: {}, sample_rate: {}", self.pcm.len(), self.channels, self.rate)
+        write!(f, "pcm.len() = {}, channels: {}, sample_rate: {}, layout: {:?}", self.pcm.len(), self.channels, self.rate, self.layout)
+    }
+}
+
+pub fn decode_file(file_path: &Path) -> Result<DecodedFile, Box<dyn Error>> {
+    let extension = file_path.extension().and_then(|extension| extension.to_str()).unwrap_or_default();
+    decode(read_audio_file(file_path)?, read_audio_file(file_path)?, extension)
+}
+
+/// Decodes an encoded stream piped to stdin. The format is guessed from the contents.
+pub fn decode_stdin() -> Result<DecodedFile, Box<dyn Error>> {
+    let mut encoded = Vec::new();
+    std::io::stdin().lock().read_to_end(&mut encoded)?;
+    let encoded: Arc<[u8]> = encoded.into();
+
+    decode(Cursor::new(encoded.clone()), Cursor::new(encoded), "")
+}
+
+/// Reads headerless interleaved samples from stdin, skipping the decoder altogether.
+/// Like the decoded files the samples are converted to 16 bit, clamping anything beyond full scale.
+pub fn read_raw_stdin(format: &RawFormat, channels: u32, rate: u32) -> Result<DecodedFile, Box<dyn Error>> {
+    let mut bytes = Vec::new();
+    std::io::stdin().lock().read_to_end(&mut bytes)?;
+    parse_raw(&bytes, format, channels, rate)
+}
+
+fn parse_raw(bytes: &[u8], format: &RawFormat, channels: u32, rate: u32) -> Result<DecodedFile, Box<dyn Error>> {
+    let pcm: Vec<i16> = match format {
+        RawFormat::S16le => bytes.chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect(),
+        RawFormat::S24le => bytes.chunks_exact(3).map(|b| (i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 16) as i16).collect(),
+        RawFormat::S32le => bytes.chunks_exact(4).map(|b| (i32::from_le_bytes([b[0], b[1], b[2], b[3]]) >> 16) as i16).collect(),
+        RawFormat::F32le => bytes.chunks_exact(4).map(|b| (f32::from_le_bytes([b[0], b[1], b[2], b[3]]) * 32768.0).clamp(i16::MIN as f32, i16::MAX as f32) as i16).collect(),
+    };
+    if channels == 0 || !pcm.len().is_multiple_of(channels as usize) {
+        return Err(format!("The raw input does not end on a whole frame of {} channels", channels).into());
     }
+
+    Ok(DecodedFile::new(pcm, channels, rate))
 }
 
-pub fn decode_file(file_path: &str) -> Result<DecodedFile, Box<dyn Error>> {
-    let audio_file = read_audio_file(file_path)?;
-    let decoder = Decoder::new(audio_file)?;
+fn decode<R, P>(audio: R, probe: P, extension: &str) -> Result<DecodedFile, Box<dyn Error>>
+    where R: Read + Seek + Send + Sync + 'static,
+          P: MediaSource + 'static {
+    let decoder = Decoder::new(audio)?;
     let channels = decoder.channels();
     assert_ne!(channels, 0);
 
     let rate = decoder.sample_rate();
-    Ok(DecodedFile::new(decoder.collect(), channels as u32, rate))
+    let layout = probe_layout(probe, extension, channels as u32);
+    Ok(DecodedFile::new(decoder.collect(), channels as u32, rate).with_layout(layout))
 }
 
-fn read_audio_file(path: &str) -> std::io::Result<fs::File> {
-    let path = Path::new(path);
+fn probe_layout(source: impl MediaSource + 'static, extension: &str, channels: u32) -> Vec<Channels> {
+    // rodio does not tell which speakers the channels belong to, so ask the demuxer directly
+    let mut hint = Hint::new();
+    hint.with_extension(extension);
+
+    let source = MediaSourceStream::new(Box::new(source), Default::default());
+    // the demuxer does not know every format the decoder plays, those get the default order of their channel count
+    let mask = get_probe().format(&hint, source, &FormatOptions::default(), &MetadataOptions::default()).ok()
+        .and_then(|probed| probed.format.default_track().and_then(|track| track.codec_params.channels));
+
+    speaker_layout(mask, channels, extension)
+}
+
+fn read_audio_file(path: &Path) -> std::io::Result<fs::File> {
     File::open(path)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_raw_samples_to_16_bit() {
+        let parse = |bytes: &[u8], format: RawFormat| parse_raw(bytes, &format, 1, 48_000).expect("To be raw samples").pcm;
+        assert_eq!(parse(&[0x34, 0x12, 0x00, 0x80], RawFormat::S16le), [0x1234, i16::MIN]);
+        assert_eq!(parse(&[0xff, 0x34, 0x12, 0xff, 0xff, 0x7f], RawFormat::S24le), [0x1234, i16::MAX]);
+        assert_eq!(parse(&[0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x80], RawFormat::S32le), [0x1234, i16::MIN]);
+
+        let floats: Vec<u8> = [0.5f32, -2.0, 1.0].iter().flat_map(|sample| sample.to_le_bytes()).collect();
+        assert_eq!(parse(&floats, RawFormat::F32le), [16384, i16::MIN, i16::MAX]);
+    }
+
+    #[test]
+    fn needs_whole_frames() {
+        let raw = parse_raw(&[0; 8], &RawFormat::S16le, 2, 44_100).expect("To be two stereo frames");
+        assert_eq!((raw.pcm.len(), raw.channels, raw.rate), (4, 2, 44_100));
+        assert!(parse_raw(&[0; 6], &RawFormat::S16le, 2, 44_100).is_err());
+        assert!(parse_raw(&[0; 4], &RawFormat::S16le, 0, 44_100).is_err());
+    }
+}
@@ -0,0 +1,78 @@
+use std::error::Error;
+use std::ffi::OsString;
+use std::path::Path;
+
+use subprocess::{ExitStatus, Popen, PopenConfig, Redirection};
+
+/// Runs ffmpeg or ffprobe and returns what it printed to stdout.
+pub fn run(args: &[OsString]) -> Result<String, Box<dyn Error>> {
+    let mut p = Popen::create(args, PopenConfig {
+        stdin: Redirection::Pipe,
+        stdout: Redirection::Pipe,
+        stderr: Redirection::Pipe,
+        detached: false,
+        executable: None,
+        env: None,
+        cwd: None,
+        setuid: None,
+        setgid: None,
+        setpgid: false,
+        _use_default_to_construct: (),
+    })?;
+    // read the pipes until the process exits, otherwise a chatty process blocks once the pipe buffer is full,
+    // the empty input closes stdin so ffmpeg never waits for keyboard commands
+    let (stdout, stderr) = p.communicate(Some(""))?;
+    let exit_code = p.wait()?;
+
+    match exit_code {
+        ExitStatus::Exited(0) => Ok(stdout.unwrap_or_default()),
+        _ => Err(format!("{} failed: {}", args[0].to_string_lossy(), stderr.unwrap_or_default().trim()).into()),
+    }
+}
+
+/// Asks ffprobe for `entries` of the first audio stream, e.g. `stream=codec_name,bit_rate`.
+pub fn probe_audio_stream(filepath: &Path, entries: &str) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+    let output = run(&[
+        "ffprobe".into(),
+        "-v".into(),
+        "error".into(),
+        "-select_streams".into(),
+        "a:0".into(),
+        "-show_entries".into(),
+        entries.into(),
+        "-of".into(),
+        "default=noprint_wrappers=1".into(),
+        filepath.into(),
+    ])?;
+
+    Ok(output.lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect())
+}
+
+/// Reads the tags of the container and of every stream, Ogg files keep theirs in the stream.
+pub fn read_tags(filepath: &Path) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+    let output = run(&[
+        "ffprobe".into(),
+        "-v".into(),
+        "error".into(),
+        "-show_entries".into(),
+        "format_tags:stream_tags".into(),
+        "-of".into(),
+        "json".into(),
+        filepath.into(),
+    ])?;
+    let probe: serde_json::Value = serde_json::from_str(&output)?;
+
+    let streams = probe["streams"].as_array().into_iter().flatten().map(|stream| &stream["tags"]);
+    let mut res: Vec<(String, String)> = Vec::new();
+    for tags in std::iter::once(&probe["format"]["tags"]).chain(streams).filter_map(|tags| tags.as_object()) {
+        for (key, value) in tags {
+            if !res.iter().any(|(existing, _)| existing == key) {
+                res.push((key.clone(), value.as_str().unwrap_or_default().to_string()));
+            }
+        }
+    }
+    Ok(res)
+}
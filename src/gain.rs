@@ -1,18 +1,50 @@
 use crate::args::ARGS;
 use crate::loudness_types::{Decibel, LinearLoudness, LoudnessUnitFullScale};
 
+// RFC 7845 makes the R128 gains of Opus files relative to the EBU R128 target instead of the ReplayGain reference
+pub const R128_REFERENCE: f64 = -23.0;
+
 pub fn calculate_gain(int_loudness: LoudnessUnitFullScale, true_peak: LinearLoudness) -> Decibel {
-    let reference_loudness = LoudnessUnitFullScale::new(-18.0);
+    gain_to(reference_loudness(), int_loudness, true_peak)
+}
 
-    let gain = (reference_loudness - int_loudness).as_dB() + ARGS.pregain;
+/// The gain bringing the loudness to `reference`, lowered with `--noclip` if the true peak would clip.
+pub fn gain_to(reference: LoudnessUnitFullScale, int_loudness: LoudnessUnitFullScale, true_peak: LinearLoudness) -> Decibel {
+    let gain = (reference - int_loudness).as_dB();
 
     if ARGS.no_clip { avoid_clipping(gain, true_peak) } else { gain }
 }
 
-fn avoid_clipping(gain: Decibel, true_peak: LinearLoudness) -> Decibel {
-    let peak_after_gain = gain.as_linear() * true_peak;
+/// The loudness files are normalized to: the reference raised by the pregain. This is also the value of the
+/// REPLAYGAIN_REFERENCE_LOUDNESS tag, the stored gain is relative to it.
+pub fn reference_loudness() -> LoudnessUnitFullScale {
+    ARGS.reference + ARGS.pregain.as_LUFS()
+}
+
+/// The gain moved from the ReplayGain reference to the R128 one, keeping the pregain.
+pub fn r128_gain(gain: Decibel) -> Decibel {
+    gain + Decibel::new(R128_REFERENCE - ARGS.reference.as_f64())
+}
+
+pub fn avoid_clipping(gain: Decibel, true_peak: LinearLoudness) -> Decibel {
+    if clips(gain, true_peak) {
+        gain - (gain.as_linear() * true_peak / ARGS.maxtlp.as_linear()).as_dB()
+    } else { gain }
+}
 
-    if peak_after_gain > ARGS.maxtlp.as_linear() {
-        gain - (peak_after_gain / ARGS.maxtlp.as_linear()).as_dB()
-    } else { peak_after_gain.as_dB() }
-}
\ No newline at end of file
+/// Whether the true peak would exceed the `--maxtpl` ceiling once the gain is applied.
+pub fn clips(gain: Decibel, true_peak: LinearLoudness) -> bool {
+    gain.as_linear() * true_peak > ARGS.maxtlp.as_linear()
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn r128_gains_are_relative_to_the_r128_reference() {
+        // 2 dB for the default -18 LUFS reference, so the track is -20 LUFS and needs -3 dB for -23 LUFS
+        assert_eq!(r128_gain(Decibel::new(2.0)).as_f64(), -3.0);
+        assert_eq!(r128_gain(Decibel::new(2.0)).to_q78num(), -768);
+        assert_eq!(r128_gain(Decibel::new(-5.5)).to_q78num(), -2688);
+    }
+}
@@ -1,6 +1,29 @@
+pub mod ape_tag;
+pub mod apply_gain;
 pub mod args;
+pub mod channel_layout;
+pub mod compliance;
+pub mod config;
+pub mod cue_sheet;
 pub mod decode_audio;
+mod ffmpeg;
 pub mod replaygain_scanner;
 pub mod loudness_types;
+pub mod loudness_statistics;
+pub mod mp3_gain;
+mod mp3_tags;
+mod mp4_tags;
+pub mod path_display;
+pub mod path_filter;
+mod raw_tags;
 mod gain;
-pub mod tags;
\ No newline at end of file
+pub mod playlist;
+pub mod report;
+pub mod resample;
+pub mod retarget;
+pub mod stored_tags;
+pub mod tag_backup;
+pub mod tag_filter;
+pub mod tags;
+pub mod timeline;
+pub mod verify;
\ No newline at end of file
@@ -0,0 +1,174 @@
+use crate::loudness_types::LoudnessUnitFullScale;
+use crate::replaygain_scanner::{LoudnessPoint, TIMELINE_STEPS_PER_SECOND};
+
+const ABSOLUTE_GATE: f64 = -70.0;
+// BS.1770 gates the blocks 10 LU below their mean to compute the integrated loudness
+const RELATIVE_GATE: f64 = -10.0;
+// EBU Tech 3342 gates the short-term loudness 20 LU below its mean to compute the loudness range
+const LRA_RELATIVE_GATE: f64 = -20.0;
+const LRA_LOW_PERCENTILE: f64 = 0.10;
+const LRA_HIGH_PERCENTILE: f64 = 0.95;
+const MOMENTARY_WINDOW: f64 = 0.4;
+const SHORT_TERM_WINDOW: f64 = 3.0;
+
+#[derive(Debug, Clone)]
+pub struct GatingStatistics {
+    /// Gating blocks above the absolute gate in 1 LU wide bins, loudest bin last.
+    pub histogram: Vec<HistogramBin>,
+    /// Share of the gating blocks discarded by the relative gate, silence included.
+    pub below_relative_gate: f64,
+    /// Share of the gating blocks below the -70 LUFS absolute gate.
+    pub silence: f64,
+    pub relative_threshold: LoudnessUnitFullScale,
+    pub loudness_range_low: LoudnessUnitFullScale,
+    pub loudness_range_high: LoudnessUnitFullScale,
+}
+
+impl GatingStatistics {
+    /// Statistics of a file whose audio was not measured.
+    pub fn unknown() -> Self {
+        GatingStatistics {
+            histogram: Vec::new(),
+            below_relative_gate: f64::NAN,
+            silence: f64::NAN,
+            relative_threshold: LoudnessUnitFullScale::new(f64::NAN),
+            loudness_range_low: LoudnessUnitFullScale::new(f64::NAN),
+            loudness_range_high: LoudnessUnitFullScale::new(f64::NAN),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct HistogramBin {
+    pub loudness: LoudnessUnitFullScale,
+    pub blocks: usize,
+}
+
+/// The gating blocks of the timeline, the loudness of every 400 ms window taken 100 ms apart.
+pub fn gating_blocks(timeline: &[LoudnessPoint]) -> impl Iterator<Item=f64> + '_ {
+    // the first points of the timeline cover less audio than the measurement window, so they are not gating blocks
+    timeline.iter().filter(|point| point.time >= MOMENTARY_WINDOW).map(|point| point.momentary.as_f64())
+}
+
+/// BS.1770 integrated loudness of the given gating blocks, which may come from several files.
+pub fn integrated_loudness(blocks: impl Iterator<Item=f64>) -> LoudnessUnitFullScale {
+    let gated: Vec<f64> = blocks.filter(|block| *block >= ABSOLUTE_GATE).collect();
+    let relative_gate = mean_loudness(&gated) + RELATIVE_GATE;
+    let gated: Vec<f64> = gated.into_iter().filter(|block| *block >= relative_gate).collect();
+
+    LoudnessUnitFullScale::new(mean_loudness(&gated))
+}
+
+fn mean_loudness(blocks: &[f64]) -> f64 {
+    if blocks.is_empty() {
+        return f64::NEG_INFINITY;
+    }
+    let mean_energy = blocks.iter().map(|loudness| 10f64.powf(loudness / 10.0)).sum::<f64>() / blocks.len() as f64;
+    10.0 * mean_energy.log10()
+}
+
+pub fn gating_statistics(timeline: &[LoudnessPoint], relative_threshold: f64) -> GatingStatistics {
+    let blocks: Vec<f64> = gating_blocks(timeline).collect();
+    // like ebur128 the short-term blocks for the loudness range are taken every second, not every timeline point.
+    // The points are counted rather than their times compared, as the steps are rounded to whole frames and
+    // drift off whole seconds at rates like 11025 Hz
+    let short_term: Vec<f64> = timeline.iter()
+        .skip_while(|point| point.time < SHORT_TERM_WINDOW)
+        .step_by(TIMELINE_STEPS_PER_SECOND)
+        .map(|point| point.short_term.as_f64())
+        .collect();
+
+    let (loudness_range_low, loudness_range_high) = loudness_range_percentiles(short_term);
+
+    GatingStatistics {
+        histogram: histogram(&blocks),
+        below_relative_gate: proportion(&blocks, |block| block < relative_threshold),
+        silence: proportion(&blocks, |block| block < ABSOLUTE_GATE),
+        relative_threshold: LoudnessUnitFullScale::new(relative_threshold),
+        loudness_range_low: LoudnessUnitFullScale::new(loudness_range_low),
+        loudness_range_high: LoudnessUnitFullScale::new(loudness_range_high),
+    }
+}
+
+fn histogram(blocks: &[f64]) -> Vec<HistogramBin> {
+    let mut bins: Vec<HistogramBin> = Vec::new();
+    let mut gated: Vec<f64> = blocks.iter().copied().filter(|block| *block >= ABSOLUTE_GATE).collect();
+    gated.sort_by(|a, b| a.partial_cmp(b).expect("To be a comparable loudness"));
+
+    for block in gated {
+        let loudness = block.floor();
+        match bins.last_mut() {
+            Some(bin) if bin.loudness.as_f64() == loudness => bin.blocks += 1,
+            _ => bins.push(HistogramBin { loudness: LoudnessUnitFullScale::new(loudness), blocks: 1 }),
+        }
+    }
+
+    bins
+}
+
+fn proportion(blocks: &[f64], predicate: impl Fn(f64) -> bool) -> f64 {
+    if blocks.is_empty() {
+        return 0.0;
+    }
+    blocks.iter().filter(|block| predicate(**block)).count() as f64 / blocks.len() as f64
+}
+
+fn loudness_range_percentiles(short_term: Vec<f64>) -> (f64, f64) {
+    let mut gated: Vec<f64> = short_term.into_iter().filter(|loudness| *loudness >= ABSOLUTE_GATE).collect();
+    if gated.is_empty() {
+        return (f64::NEG_INFINITY, f64::NEG_INFINITY);
+    }
+
+    let relative_gate = mean_loudness(&gated) + LRA_RELATIVE_GATE;
+    gated.retain(|loudness| *loudness >= relative_gate);
+    gated.sort_by(|a, b| a.partial_cmp(b).expect("To be a comparable loudness"));
+
+    (percentile(&gated, LRA_LOW_PERCENTILE), percentile(&gated, LRA_HIGH_PERCENTILE))
+}
+
+fn percentile(sorted: &[f64], percentile: f64) -> f64 {
+    sorted[((sorted.len() - 1) as f64 * percentile).round() as usize]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(time: f64, momentary: f64, short_term: f64) -> LoudnessPoint {
+        LoudnessPoint::new(time, momentary, short_term)
+    }
+
+    #[test]
+    fn takes_the_short_term_blocks_every_second_at_any_rate() {
+        // 11025 Hz steps are rounded to 1103 frames, so no point falls on a whole second after the first one
+        let timeline: Vec<LoudnessPoint> = (1..=100)
+            .map(|step| point(step as f64 * 1103.0 / 11025.0, -20.0, -30.0 + step as f64 / 10.0))
+            .collect();
+        let statistics = gating_statistics(&timeline, -30.0);
+
+        assert_eq!(statistics.loudness_range_low.as_f64(), -26.0);
+        assert_eq!(statistics.loudness_range_high.as_f64(), -20.0);
+    }
+
+    #[test]
+    fn gates_the_momentary_blocks() {
+        let momentary = [-10.0, -10.0, -10.0, -80.0, -30.5, -30.2, -29.9, -20.0, -20.4, -45.0];
+        let timeline: Vec<LoudnessPoint> = momentary.iter().enumerate()
+            .map(|(step, loudness)| point((step + 1) as f64 / 10.0, *loudness, f64::NEG_INFINITY))
+            .collect();
+        let statistics = gating_statistics(&timeline, -31.0);
+
+        let bins: Vec<(f64, usize)> = statistics.histogram.iter().map(|bin| (bin.loudness.as_f64(), bin.blocks)).collect();
+        assert_eq!(bins, [(-45.0, 1), (-31.0, 2), (-30.0, 1), (-21.0, 1), (-20.0, 1)]);
+        assert_eq!(statistics.below_relative_gate, 2.0 / 7.0);
+        assert_eq!(statistics.silence, 1.0 / 7.0);
+        assert_eq!(statistics.loudness_range_low.as_f64(), f64::NEG_INFINITY);
+    }
+
+    #[test]
+    fn measures_the_integrated_loudness_of_the_gated_blocks() {
+        let loudness = integrated_loudness([-20.0, -80.0, -20.0, -40.0].into_iter());
+        assert!((loudness.as_f64() + 20.0).abs() < 1e-9);
+        assert_eq!(integrated_loudness([-80.0].into_iter()).as_f64(), f64::NEG_INFINITY);
+    }
+}
@@ -23,8 +23,9 @@ impl Decibel {
     }
     #[allow(non_snake_case)] pub fn as_LUFS(&self) -> LoudnessUnitFullScale { LoudnessUnitFullScale::new(self.0) }
     #[allow(non_snake_case)] pub fn as_LU(&self) -> LoudnessUnit { LoudnessUnit::new(self.0) }
-    pub fn as_linear(&self) -> LinearLoudness { LinearLoudness::new((self.0 / 20.0).powi(10)) }
+    pub fn as_linear(&self) -> LinearLoudness { LinearLoudness::new(10f64.powf(self.0 / 20.0)) }
     pub fn to_q78num(&self) -> i32 { (self.0 * 256.0).round() as i32 }
+    pub fn as_f64(&self) -> f64 { self.0 }
 }
 
 impl Add for Decibel {
@@ -87,6 +88,15 @@ impl LoudnessUnitFullScale {
     }
     #[allow(non_snake_case)] pub fn as_dB(&self) -> Decibel { Decibel::new(self.0) }
     pub fn as_linear(&self) -> LinearLoudness { self.as_dB().as_linear() }
+    pub fn as_f64(&self) -> f64 { self.0 }
+}
+
+impl PartialOrd for LoudnessUnitFullScale {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> { self.0.partial_cmp(&other.0) }
+}
+
+impl PartialEq<Self> for LoudnessUnitFullScale {
+    fn eq(&self, other: &Self) -> bool { self.0 == other.0 }
 }
 
 impl FromStr for LoudnessUnitFullScale {
@@ -130,6 +140,7 @@ impl LinearLoudness {
         Self(val)
     }
     #[allow(non_snake_case)] pub fn as_dB(&self) -> Decibel { Decibel::new(20.0 * self.0.log10()) }
+    pub fn as_f64(&self) -> f64 { self.0 }
 }
 
 impl Add for LinearLoudness {
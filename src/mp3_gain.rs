@@ -0,0 +1,386 @@
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use crate::ape_tag::{ApeTag, trailing_tags_start, write_ape_tag};
+use crate::loudness_types::Decibel;
+use crate::tags::replace_file_contents;
+
+// every global_gain step changes the volume by 2^(1/4)
+pub const GAIN_STEP: f64 = 1.5;
+const UNDO_KEY: &str = "MP3GAIN_UNDO";
+const MINMAX_KEY: &str = "MP3GAIN_MINMAX";
+
+const MPEG1_BITRATES: [u32; 15] = [0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320];
+const MPEG2_BITRATES: [u32; 15] = [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+const MPEG1_RATES: [u32; 3] = [44100, 48000, 32000];
+
+/// A Layer III frame, located in the file.
+struct Frame {
+    offset: usize,
+    length: usize,
+    mpeg1: bool,
+    channels: usize,
+    protected: bool,
+}
+
+impl Frame {
+    fn parse(data: &[u8], offset: usize) -> Option<Frame> {
+        let header = data.get(offset..offset + 4)?;
+        if header[0] != 0xFF || header[1] & 0xE0 != 0xE0 {
+            return None;
+        }
+        let version = (header[1] >> 3) & 3;
+        let layer = (header[1] >> 1) & 3;
+        let bitrate_index = (header[2] >> 4) as usize;
+        let rate_index = ((header[2] >> 2) & 3) as usize;
+        // reserved values, other layers and free format streams, which cannot be walked frame by frame
+        if version == 1 || layer != 1 || bitrate_index == 0 || bitrate_index == 15 || rate_index == 3 {
+            return None;
+        }
+
+        let mpeg1 = version == 3;
+        let bitrate = if mpeg1 { MPEG1_BITRATES[bitrate_index] } else { MPEG2_BITRATES[bitrate_index] } * 1000;
+        // MPEG 2 halves the sample rates of MPEG 1 and MPEG 2.5 halves them again
+        let rate = MPEG1_RATES[rate_index] / match version { 3 => 1, 2 => 2, _ => 4 };
+        let padding = ((header[2] >> 1) & 1) as usize;
+        let length = (if mpeg1 { 144 } else { 72 } * bitrate / rate) as usize + padding;
+
+        let frame = Frame {
+            offset,
+            length,
+            mpeg1,
+            channels: if header[3] >> 6 == 3 { 1 } else { 2 },
+            protected: header[1] & 1 == 0,
+        };
+        if frame.side_info_start() + frame.side_info_length() > length {
+            return None;
+        }
+        Some(frame)
+    }
+
+    fn side_info_start(&self) -> usize {
+        if self.protected { 6 } else { 4 }
+    }
+
+    fn side_info_length(&self) -> usize {
+        match (self.mpeg1, self.channels) {
+            (true, 1) => 17,
+            (true, _) => 32,
+            (false, 1) => 9,
+            (false, _) => 17,
+        }
+    }
+
+    /// The bit position of every global_gain field and the channel it belongs to.
+    fn global_gains(&self) -> impl Iterator<Item=(usize, usize)> {
+        let (granules, first, block) = match (self.mpeg1, self.channels) {
+            (true, 1) => (2, 18, 59),
+            (true, _) => (2, 20, 59),
+            (false, 1) => (1, 9, 63),
+            (false, _) => (1, 10, 63),
+        };
+        let start = (self.offset + self.side_info_start()) * 8 + first;
+        let channels = self.channels;
+        // global_gain follows part2_3_length (12 bits) and big_values (9 bits)
+        (0..granules * channels).map(move |block_index| (block_index % channels, start + block_index * block + 21))
+    }
+
+    /// The Xing, Info and VBRI frames carry the stream information instead of audio.
+    fn is_info(&self, data: &[u8]) -> bool {
+        let after_side_info = &data[self.offset + self.side_info_start() + self.side_info_length()..];
+        after_side_info.starts_with(b"Xing") || after_side_info.starts_with(b"Info")
+            || data[self.offset..].get(36..40) == Some(b"VBRI".as_slice())
+    }
+
+    fn update_crc(&self, data: &mut [u8]) {
+        if !self.protected {
+            return;
+        }
+        // the checksum covers the last two header bytes and the side information
+        let header = &data[self.offset + 2..self.offset + 4];
+        let side_info = &data[self.offset + 6..self.offset + 6 + self.side_info_length()];
+        let crc = crc16(header.iter().chain(side_info));
+        data[self.offset + 4..self.offset + 6].copy_from_slice(&crc.to_be_bytes());
+    }
+}
+
+/// A lossless volume change, computed before the file is rewritten with its new tags.
+pub struct LosslessGain {
+    steps: i32,
+}
+
+impl LosslessGain {
+    /// The part of the gain that will be applied to the audio.
+    pub fn gain(&self) -> Decibel {
+        Decibel::new(self.steps as f64 * GAIN_STEP)
+    }
+
+    /// Changes the global gain of every frame and records how to undo it in the APE tag.
+    pub fn apply(&self, filepath: &Path) -> Result<(), Box<dyn Error>> {
+        if self.steps == 0 {
+            return Ok(());
+        }
+
+        let mut data = fs::read(filepath)?;
+        let frames = audio_frames(&data);
+        change_gain(&mut data, &frames, [self.steps, self.steps]);
+        let (min, max) = gain_range(&data, &frames).ok_or("The file does not contain any MP3 frames")?;
+
+        let mut tag = ApeTag::read(&data).map(|(tag, _)| tag).unwrap_or_default();
+        let (left, right) = undo_steps(&tag).unwrap_or((0, 0));
+        tag.set(UNDO_KEY, &format!("{:+04},{:+04},N", left - self.steps, right - self.steps));
+        tag.set(MINMAX_KEY, &format!("{:03},{:03}", min, max));
+        write_ape_tag(&mut data, &tag);
+
+        replace_file_contents(filepath, &data)
+    }
+}
+
+pub fn is_mp3(filepath: &Path) -> bool {
+    filepath.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("mp3"))
+}
+
+/// Rounds the gain to whole global_gain steps, without letting any frame leave the 0-255 range.
+/// Rounding down never ends up louder than `gain`, which keeps a clipping-adjusted gain below the ceiling.
+pub fn prepare_lossless_gain(filepath: &Path, gain: Decibel, round_down: bool) -> Result<LosslessGain, Box<dyn Error>> {
+    let data = fs::read(filepath)?;
+    let frames = audio_frames(&data);
+    let (min, max) = gain_range(&data, &frames).ok_or("The file does not contain any MP3 frames")?;
+
+    let steps = gain.as_f64() / GAIN_STEP;
+    let steps = if round_down { steps.floor() } else { steps.round() } as i32;
+    // a wrapped around global_gain turns the quietest frames into the loudest ones
+    let steps = steps.clamp(-(min as i32), 255 - max as i32);
+
+    Ok(LosslessGain { steps })
+}
+
+/// Reverts the changes recorded in the APE tag and returns the gain that was taken back.
+pub fn undo_lossless_gain(filepath: &Path) -> Result<Option<Decibel>, Box<dyn Error>> {
+    let mut data = fs::read(filepath)?;
+    let mut tag = match ApeTag::read(&data) {
+        Some((tag, _)) => tag,
+        None => return Ok(None),
+    };
+    let (left, right) = match undo_steps(&tag) {
+        Some(steps) => steps,
+        None => return Ok(None),
+    };
+
+    let frames = audio_frames(&data);
+    change_gain(&mut data, &frames, [left, right]);
+    tag.remove(UNDO_KEY);
+    tag.remove(MINMAX_KEY);
+    write_ape_tag(&mut data, &tag);
+    replace_file_contents(filepath, &data)?;
+
+    Ok(Some(Decibel::new(left as f64 * GAIN_STEP)))
+}
+
+fn audio_frames(data: &[u8]) -> Vec<Frame> {
+    let end = trailing_tags_start(data);
+    let mut frames = Vec::new();
+    let mut pos = id3v2_length(data);
+
+    while pos + 4 <= end {
+        match Frame::parse(&data[..end], pos) {
+            // a sync word can show up inside the audio data, so the next frame has to follow right after
+            Some(frame) if pos + frame.length == end || (pos + frame.length < end && Frame::parse(&data[..end], pos + frame.length).is_some()) => {
+                pos += frame.length;
+                if !frame.is_info(data) {
+                    frames.push(frame);
+                }
+            }
+            _ => pos += 1,
+        }
+    }
+
+    frames
+}
+
+pub(crate) fn id3v2_length(data: &[u8]) -> usize {
+    if data.len() < 10 || &data[0..3] != b"ID3" {
+        return 0;
+    }
+    // the size is stored in 7 bits per byte and excludes the header and the footer
+    let size = data[6..10].iter().fold(0, |size, byte| (size << 7) | (*byte as usize & 0x7F));
+    let footer = if data[5] & 0x10 != 0 { 10 } else { 0 };
+    10 + size + footer
+}
+
+fn gain_range(data: &[u8], frames: &[Frame]) -> Option<(u8, u8)> {
+    let gains = frames.iter().flat_map(|frame| frame.global_gains()).map(|(_, bit)| read_byte(data, bit));
+    gains.fold(None, |range, gain| match range {
+        None => Some((gain, gain)),
+        Some((min, max)) => Some((min.min(gain), max.max(gain))),
+    })
+}
+
+fn change_gain(data: &mut [u8], frames: &[Frame], steps: [i32; 2]) {
+    for frame in frames {
+        for (channel, bit) in frame.global_gains() {
+            let gain = (read_byte(data, bit) as i32 + steps[channel]).clamp(0, 255);
+            write_byte(data, bit, gain as u8);
+        }
+        frame.update_crc(data);
+    }
+}
+
+fn undo_steps(tag: &ApeTag) -> Option<(i32, i32)> {
+    let undo = tag.get(UNDO_KEY)?;
+    let mut fields = undo.split(',');
+    let left = fields.next()?.trim().parse().ok()?;
+    let right = fields.next()?.trim().parse().ok()?;
+    Some((left, right))
+}
+
+fn read_byte(data: &[u8], bit: usize) -> u8 {
+    let word = u16::from_be_bytes([data[bit / 8], data[bit / 8 + 1]]);
+    ((word << (bit % 8)) >> 8) as u8
+}
+
+fn write_byte(data: &mut [u8], bit: usize, value: u8) {
+    let shift = bit % 8;
+    let word = u16::from_be_bytes([data[bit / 8], data[bit / 8 + 1]]);
+    let mask = 0xFF00 >> shift;
+    let word = (word & !mask) | ((value as u16) << (8 - shift));
+    data[bit / 8..bit / 8 + 2].copy_from_slice(&word.to_be_bytes());
+}
+
+fn crc16<'a>(bytes: impl Iterator<Item=&'a u8>) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for byte in bytes {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x8005 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    // MPEG 1 Layer III at 128 kbit/s and 44.1 kHz, 417 bytes long
+    const STEREO_HEADER: [u8; 4] = [0xFF, 0xFB, 0x90, 0x00];
+    const PROTECTED_MONO_HEADER: [u8; 4] = [0xFF, 0xFA, 0x90, 0xC0];
+    const FRAME_LENGTH: usize = 417;
+
+    fn frame(header: [u8; 4], global_gain: u8) -> Vec<u8> {
+        let mut res = header.to_vec();
+        res.resize(FRAME_LENGTH, 0);
+        let frame = Frame::parse(&res, 0).expect("To be a frame");
+        for (_, bit) in frame.global_gains() {
+            write_byte(&mut res, bit, global_gain);
+        }
+        frame.update_crc(&mut res);
+        res
+    }
+
+    fn id3v2_tag() -> Vec<u8> {
+        let mut res = b"ID3\x04\x00\x00\x00\x00\x01\x00".to_vec();
+        // a sync word in the tag must not be taken for a frame
+        res.extend(STEREO_HEADER);
+        res.resize(10 + 128, 0);
+        res
+    }
+
+    fn mp3(frames: &[Vec<u8>]) -> Vec<u8> {
+        let mut res = id3v2_tag();
+        frames.iter().for_each(|frame| res.extend(frame));
+        let mut tag = ApeTag::default();
+        tag.set("REPLAYGAIN_TRACK_GAIN", "-6.00 dB");
+        write_ape_tag(&mut res, &tag);
+        res
+    }
+
+    #[test]
+    fn parses_frame_headers() {
+        let data = frame(STEREO_HEADER, 0);
+        let frame = Frame::parse(&data, 0).expect("To be a frame");
+        assert_eq!((frame.length, frame.mpeg1, frame.channels, frame.protected), (417, true, 2, false));
+        assert_eq!(frame.global_gains().count(), 4);
+
+        let data = self::frame(PROTECTED_MONO_HEADER, 0);
+        let frame = Frame::parse(&data, 0).expect("To be a frame");
+        assert_eq!((frame.channels, frame.protected, frame.side_info_start()), (1, true, 6));
+        assert_eq!(frame.global_gains().collect::<Vec<_>>(), [(0, 6 * 8 + 18 + 21), (0, 6 * 8 + 18 + 59 + 21)]);
+
+        // Layer II and free format
+        assert!(Frame::parse(&[0xFF, 0xFD, 0x90, 0x00], 0).is_none());
+        assert!(Frame::parse(&[0xFF, 0xFB, 0x00, 0x00], 0).is_none());
+    }
+
+    #[test]
+    fn reads_and_writes_unaligned_bytes() {
+        let mut data = [0b1010_1010, 0b0101_0101, 0xFF];
+        assert_eq!(read_byte(&data, 3), 0b0101_0010);
+        write_byte(&mut data, 3, 0xFF);
+        assert_eq!(data, [0b1011_1111, 0b1111_0101, 0xFF]);
+        write_byte(&mut data, 8, 0);
+        assert_eq!(data, [0b1011_1111, 0, 0xFF]);
+    }
+
+    #[test]
+    fn computes_the_frame_checksum() {
+        assert_eq!(crc16(b"123456789".iter()), 0xAEE7);
+
+        let data = frame(PROTECTED_MONO_HEADER, 140);
+        let side_info = &data[6..6 + 17];
+        assert_eq!(data[4..6], crc16(data[2..4].iter().chain(side_info)).to_be_bytes());
+    }
+
+    #[test]
+    fn finds_the_audio_frames_between_the_tags() {
+        let mut info = frame(STEREO_HEADER, 0);
+        info[4 + 32..4 + 36].copy_from_slice(b"Info");
+        let data = mp3(&[info, frame(STEREO_HEADER, 150), frame(STEREO_HEADER, 160)]);
+
+        let frames = audio_frames(&data);
+        let offsets: Vec<usize> = frames.iter().map(|frame| frame.offset).collect();
+        assert_eq!(offsets, [138 + FRAME_LENGTH, 138 + 2 * FRAME_LENGTH]);
+        assert_eq!(gain_range(&data, &frames), Some((150, 160)));
+    }
+
+    #[test]
+    fn changes_the_gain_per_channel() {
+        let mut data = mp3(&[frame(STEREO_HEADER, 150), frame(PROTECTED_MONO_HEADER, 250)]);
+        let frames = audio_frames(&data);
+        change_gain(&mut data, &frames, [10, -5]);
+
+        let gains: Vec<(usize, u8)> = frames.iter().flat_map(|frame| frame.global_gains()).map(|(channel, bit)| (channel, read_byte(&data, bit))).collect();
+        assert_eq!(gains, [(0, 160), (1, 145), (0, 160), (1, 145), (0, 255), (0, 255)]);
+        let mono = &frames[1];
+        let crc = crc16(data[mono.offset + 2..mono.offset + 4].iter().chain(&data[mono.offset + 6..mono.offset + 6 + 17]));
+        assert_eq!(data[mono.offset + 4..mono.offset + 6], crc.to_be_bytes());
+    }
+
+    #[test]
+    fn applies_and_undoes_the_gain() {
+        let original = mp3(&[frame(STEREO_HEADER, 150), frame(STEREO_HEADER, 250)]);
+        let mut file = tempfile::Builder::new().suffix(".mp3").tempfile().expect("To create a temp file");
+        file.write_all(&original).expect("To write the temp file");
+
+        // the loudest frame leaves room for 5 steps
+        let gain = prepare_lossless_gain(file.path(), Decibel::new(9.0), false).expect("To read the MP3");
+        assert_eq!(gain.gain().as_f64(), 7.5);
+        let gain = prepare_lossless_gain(file.path(), Decibel::new(-4.0), true).expect("To read the MP3");
+        assert_eq!(gain.gain().as_f64(), -4.5);
+
+        gain.apply(file.path()).expect("To apply the gain");
+        let data = fs::read(file.path()).expect("To read the MP3");
+        let (tag, _) = ApeTag::read(&data).expect("To be a tag");
+        assert_eq!(tag.get(UNDO_KEY).as_deref(), Some("+003,+003,N"));
+        assert_eq!(tag.get(MINMAX_KEY).as_deref(), Some("147,247"));
+        assert_eq!(gain_range(&data, &audio_frames(&data)), Some((147, 247)));
+
+        let undone = undo_lossless_gain(file.path()).expect("To undo the gain");
+        assert_eq!(undone.map(|gain| gain.as_f64()), Some(4.5));
+        assert_eq!(fs::read(file.path()).expect("To read the MP3"), original);
+        assert!(undo_lossless_gain(file.path()).expect("To read the MP3").is_none());
+    }
+}
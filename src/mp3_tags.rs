@@ -0,0 +1,330 @@
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use crate::ape_tag::{ApeTag, id3v1_start, write_ape_tag};
+use crate::args::{ApeTagHandling, ARGS, Id3v1Handling};
+use crate::loudness_types::{Decibel, LinearLoudness};
+use crate::replaygain_scanner::TrackGain;
+use crate::tag_filter::{filters_tags, keep_tag};
+use crate::tags::{is_rg_tag, replace_file_contents, rg_values, track_peak};
+
+const ID3V2_HEADER_SIZE: usize = 10;
+const RVA2_IDENTIFICATION: &[u8] = b"track\0";
+const RVA2_MASTER_VOLUME: u8 = 1;
+const RVA2_PEAK_BITS: u8 = 16;
+
+/// ffmpeg arguments for the ID3v2 version chosen with `-I`.
+pub(crate) fn ffmpeg_args() -> Vec<String> {
+    vec!["-id3v2_version".to_string(), ARGS.id3v2_version.to_string()]
+}
+
+/// ffmpeg only writes the ID3v2 tag, so the APE and ID3v1 tags of the original are appended to its output.
+pub(crate) fn carry_over_trailing_tags(original: &Path, rewritten: &Path) -> Result<(), Box<dyn Error>> {
+    let data = fs::read(original)?;
+    let mut res = fs::read(rewritten)?;
+    // -S strips the other tag types, like loudgain does
+    if !matches!(ARGS.ape_tags, ApeTagHandling::Strip) && !ARGS.strip_tags {
+        if let Some((tag, _)) = ApeTag::read(&data) {
+            write_ape_tag(&mut res, &tag);
+        }
+    }
+    if matches!(ARGS.id3v1, Id3v1Handling::Keep) && !ARGS.strip_tags {
+        res.extend(&data[id3v1_start(&data)..]);
+    }
+
+    fs::write(rewritten, res)?;
+    Ok(())
+}
+
+/// Filters the APE tag like the other tags and adds the ReplayGain values to it and to an RVA2 frame when asked to.
+pub(crate) fn write_mp3_tags(tags: &TrackGain) -> Result<(), Box<dyn Error>> {
+    if !filters_tags() && !matches!(ARGS.ape_tags, ApeTagHandling::Write) && !ARGS.rva2 {
+        return Ok(());
+    }
+    let mut data = fs::read(&tags.filepath)?;
+
+    let mut ape_tag = ApeTag::read(&data).map(|(tag, _)| tag).unwrap_or_default();
+    if filters_tags() {
+        ape_tag.retain(keep_tag);
+    }
+    if matches!(ARGS.ape_tags, ApeTagHandling::Write) {
+        for (key, value) in rg_values(tags, "mp3") {
+            ape_tag.set(&key, &value);
+        }
+    }
+    write_ape_tag(&mut data, &ape_tag);
+
+    if ARGS.rva2 {
+        let frame = rva2_frame(tags.gain, track_peak(tags));
+        edit_id3v2_frames(&mut data, is_track_rva2, Some((*b"RVA2", frame)))?;
+    }
+
+    replace_file_contents(&tags.filepath, &data)
+}
+
+/// Removes the ReplayGain values from the APE tag and the track RVA2 frame.
+pub(crate) fn remove_mp3_tags(filepath: &Path) -> Result<(), Box<dyn Error>> {
+    let mut data = fs::read(filepath)?;
+    if let Some((mut tag, _)) = ApeTag::read(&data) {
+        tag.retain(|key| !is_rg_tag(key));
+        write_ape_tag(&mut data, &tag);
+    }
+    edit_id3v2_frames(&mut data, is_track_rva2, None)?;
+
+    replace_file_contents(filepath, &data)
+}
+
+/// Removes the APE items whose key passes `remove`.
+pub(crate) fn remove_ape_items(filepath: &Path, remove: impl Fn(&str) -> bool) -> Result<(), Box<dyn Error>> {
+    let mut data = fs::read(filepath)?;
+    if let Some((mut tag, _)) = ApeTag::read(&data) {
+        tag.retain(|key| !remove(key));
+        write_ape_tag(&mut data, &tag);
+        replace_file_contents(filepath, &data)?;
+    }
+    Ok(())
+}
+
+/// The ReplayGain items of the APE tag and the content of the track RVA2 frame, which ffmpeg does not write back.
+pub(crate) fn read_mp3_tags(data: &[u8]) -> (Vec<(String, String)>, Option<Vec<u8>>) {
+    let ape_items = ApeTag::read(data).map(|(tag, _)| {
+        tag.keys().into_iter().filter(|key| is_rg_tag(key)).filter_map(|key| Some((key.clone(), tag.get(&key)?))).collect()
+    }).unwrap_or_default();
+    (ape_items, track_rva2(data).map(<[u8]>::to_vec))
+}
+
+/// Puts back the ReplayGain items of the APE tag and the track RVA2 frame read by `read_mp3_tags`.
+pub(crate) fn restore_mp3_tags(filepath: &Path, ape_items: &[(String, String)], rva2: Option<Vec<u8>>) -> Result<(), Box<dyn Error>> {
+    let mut data = fs::read(filepath)?;
+    let mut ape_tag = ApeTag::read(&data).map(|(tag, _)| tag).unwrap_or_default();
+    ape_tag.retain(|key| !is_rg_tag(key));
+    for (key, value) in ape_items {
+        ape_tag.set(key, value);
+    }
+    write_ape_tag(&mut data, &ape_tag);
+    if rva2.is_some() || track_rva2(&data).is_some() {
+        edit_id3v2_frames(&mut data, is_track_rva2, rva2.map(|frame| (*b"RVA2", frame)))?;
+    }
+
+    replace_file_contents(filepath, &data)
+}
+
+fn is_track_rva2(id: &[u8], content: &[u8]) -> bool {
+    id == b"RVA2" && content.starts_with(RVA2_IDENTIFICATION)
+}
+
+fn track_rva2(data: &[u8]) -> Option<&[u8]> {
+    tag_frame_locations(data).into_iter()
+        .map(|(start, end)| (&data[start..start + 4], &data[start + ID3V2_HEADER_SIZE..end]))
+        .find(|(id, content)| is_track_rva2(id, content))
+        .map(|(_, content)| content)
+}
+
+fn rva2_frame(gain: Decibel, peak: LinearLoudness) -> Vec<u8> {
+    // the adjustment is stored in 1/512 dB and the peak as a fraction of full scale
+    let adjustment = (gain.as_f64() * 512.0).round().clamp(i16::MIN as f64, i16::MAX as f64) as i16;
+    let peak = (peak.as_f64() * 32768.0).round().clamp(0.0, u16::MAX as f64) as u16;
+
+    let mut res = RVA2_IDENTIFICATION.to_vec();
+    res.push(RVA2_MASTER_VOLUME);
+    res.extend(adjustment.to_be_bytes());
+    res.push(RVA2_PEAK_BITS);
+    res.extend(peak.to_be_bytes());
+    res
+}
+
+/// Drops the frames matching `remove` from the ID3v2 tag at the start of the file and appends `add`,
+/// creating the tag if there is none. Padding is reused as far as it goes.
+fn edit_id3v2_frames(data: &mut Vec<u8>, remove: impl Fn(&[u8], &[u8]) -> bool, add: Option<([u8; 4], Vec<u8>)>) -> Result<(), Box<dyn Error>> {
+    let has_tag = data.starts_with(b"ID3") && data.len() >= ID3V2_HEADER_SIZE;
+    let (version, flags, size) = if has_tag {
+        (data[3], data[5], read_syncsafe(&data[6..10]))
+    } else {
+        (ARGS.id3v2_version.major(), 0, 0)
+    };
+    if flags & 0xC0 != 0 {
+        return Err("Cannot edit an unsynchronised ID3v2 tag or one with an extended header".into());
+    }
+    if version != 3 && version != 4 {
+        return Err(format!("Cannot edit an ID3v2.{} tag", version).into());
+    }
+    let end = if has_tag { (ID3V2_HEADER_SIZE + size).min(data.len()) } else { 0 };
+
+    let mut frames = Vec::new();
+    for (start, frame_end) in frame_locations(data, version, end) {
+        if !remove(&data[start..start + 4], &data[start + ID3V2_HEADER_SIZE..frame_end]) {
+            frames.extend(&data[start..frame_end]);
+        }
+    }
+    if let Some((id, content)) = add {
+        frames.extend(id);
+        frames.extend(if version == 4 { write_syncsafe(content.len()) } else { (content.len() as u32).to_be_bytes() });
+        frames.extend([0, 0]);
+        frames.extend(content);
+    }
+
+    let padding = size.saturating_sub(frames.len());
+    let mut tag = vec![b'I', b'D', b'3', version, 0, flags & !0x10];
+    tag.extend(write_syncsafe(frames.len() + padding));
+    tag.extend(frames);
+    tag.resize(tag.len() + padding, 0);
+
+    // a footer would repeat the header after the frames
+    let footer = if has_tag && flags & 0x10 != 0 { ID3V2_HEADER_SIZE } else { 0 };
+    data.splice(0..(end + footer).min(data.len()), tag);
+    Ok(())
+}
+
+/// Where every frame of the ID3v2 tag, which ends at `end`, starts and ends. Padding ends the frames.
+fn frame_locations(data: &[u8], version: u8, end: usize) -> Vec<(usize, usize)> {
+    let mut res = Vec::new();
+    let mut pos = ID3V2_HEADER_SIZE;
+    while pos + ID3V2_HEADER_SIZE <= end && data[pos] != 0 {
+        let size = &data[pos + 4..pos + 8];
+        let frame_size = if version == 4 { read_syncsafe(size) } else { u32::from_be_bytes(size.try_into().expect("To be 4 bytes")) as usize };
+        let frame_end = (pos + ID3V2_HEADER_SIZE + frame_size).min(end);
+        res.push((pos, frame_end));
+        pos = frame_end;
+    }
+    res
+}
+
+/// The frames of the ID3v2 tag at the start of the file, none if there is no tag or it is unsynchronised.
+fn tag_frame_locations(data: &[u8]) -> Vec<(usize, usize)> {
+    if !data.starts_with(b"ID3") || data.len() < ID3V2_HEADER_SIZE || data[5] & 0x80 != 0 {
+        return Vec::new();
+    }
+    let end = (ID3V2_HEADER_SIZE + read_syncsafe(&data[6..10])).min(data.len());
+    frame_locations(data, data[3], end)
+}
+
+/// The descriptions of the TXXX frames, which hold the ReplayGain tags, as stored in the file.
+pub(crate) fn txxx_descriptions(data: &[u8]) -> Vec<String> {
+    tag_frame_locations(data).into_iter()
+        .filter(|(start, _)| &data[*start..*start + 4] == b"TXXX")
+        .filter_map(|(start, end)| {
+            let (encoding, text) = data[start + ID3V2_HEADER_SIZE..end].split_first()?;
+            Some(decode_description(*encoding, text))
+        })
+        .collect()
+}
+
+fn decode_description(encoding: u8, text: &[u8]) -> String {
+    match encoding {
+        // UTF-16 with a byte order mark, or big endian without one
+        1 | 2 => {
+            let units: Vec<u16> = text.chunks_exact(2).map(|unit| [unit[0], unit[1]]).take_while(|unit| unit != &[0, 0]).map(|unit| {
+                if encoding == 1 && text.starts_with(&[0xFF, 0xFE]) { u16::from_le_bytes(unit) } else { u16::from_be_bytes(unit) }
+            }).collect();
+            String::from_utf16_lossy(&units).trim_start_matches('\u{FEFF}').to_string()
+        }
+        // ISO-8859-1 maps directly onto the first unicode code points
+        0 => text.iter().take_while(|byte| **byte != 0).map(|byte| *byte as char).collect(),
+        _ => String::from_utf8_lossy(text.split(|byte| *byte == 0).next().unwrap_or_default()).to_string(),
+    }
+}
+
+fn read_syncsafe(bytes: &[u8]) -> usize {
+    bytes.iter().fold(0, |size, byte| (size << 7) | (*byte as usize & 0x7F))
+}
+
+fn write_syncsafe(value: usize) -> [u8; 4] {
+    [(value >> 21) as u8 & 0x7F, (value >> 14) as u8 & 0x7F, (value >> 7) as u8 & 0x7F, value as u8 & 0x7F]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(version: u8, id: &[u8; 4], content: &[u8]) -> Vec<u8> {
+        let mut res = id.to_vec();
+        res.extend(if version == 4 { write_syncsafe(content.len()) } else { (content.len() as u32).to_be_bytes() });
+        res.extend([0, 0]);
+        res.extend(content);
+        res
+    }
+
+    fn tag(version: u8, flags: u8, frames: &[Vec<u8>], padding: usize) -> Vec<u8> {
+        let frames = frames.concat();
+        let mut res = vec![b'I', b'D', b'3', version, 0, flags];
+        res.extend(write_syncsafe(frames.len() + padding));
+        res.extend(frames);
+        res.resize(res.len() + padding, 0);
+        res
+    }
+
+    fn title(version: u8) -> Vec<u8> {
+        // long enough for the syncsafe and the plain frame sizes to differ
+        frame(version, b"TIT2", &[b"\x03".as_slice(), &[b'a'; 200]].concat())
+    }
+
+    #[test]
+    fn encodes_the_rva2_frame() {
+        let frame = rva2_frame(Decibel::new(-6.5), LinearLoudness::new(0.5));
+        assert_eq!(frame, b"track\0\x01\xF3\x00\x10\x40\x00");
+        let frame = rva2_frame(Decibel::new(100.0), LinearLoudness::new(3.0));
+        assert_eq!(frame[7..], [0x7F, 0xFF, 0x10, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn replaces_the_track_rva2_frame_in_the_padding() {
+        for version in [3, 4] {
+            let album = frame(version, b"RVA2", b"album\0\x01\x00\x00\x00");
+            let old = frame(version, b"RVA2", b"track\0\x01\x00\x00\x00");
+            let mut data = tag(version, 0, &[title(version), old, album.clone()], 64);
+            let length = data.len();
+            data.extend(b"audio");
+
+            let new = rva2_frame(Decibel::new(-6.5), LinearLoudness::new(0.5));
+            edit_id3v2_frames(&mut data, is_track_rva2, Some((*b"RVA2", new.clone()))).expect("To edit the tag");
+            let mut expected = tag(version, 0, &[title(version), album, frame(version, b"RVA2", &new)], 62);
+            expected.extend(b"audio");
+            assert_eq!(data.len(), length + 5);
+            assert_eq!(data, expected);
+        }
+    }
+
+    #[test]
+    fn grows_the_tag_and_drops_the_footer() {
+        let mut data = tag(4, 0x10, &[title(4)], 0);
+        data.extend(b"3DI\x04\x00\x10\x00\x00\x01\x53");
+        data.extend(b"audio");
+
+        let new = rva2_frame(Decibel::new(1.0), LinearLoudness::new(1.0));
+        edit_id3v2_frames(&mut data, is_track_rva2, Some((*b"RVA2", new.clone()))).expect("To edit the tag");
+        let mut expected = tag(4, 0, &[title(4), frame(4, b"RVA2", &new)], 0);
+        expected.extend(b"audio");
+        assert_eq!(data, expected);
+
+        edit_id3v2_frames(&mut data, is_track_rva2, None).expect("To edit the tag");
+        let mut expected = tag(4, 0, &[title(4)], 10 + new.len());
+        expected.extend(b"audio");
+        assert_eq!(data, expected);
+    }
+
+    #[test]
+    fn refuses_tags_it_cannot_rewrite() {
+        let mut unsynchronised = tag(4, 0x80, &[title(4)], 0);
+        assert!(edit_id3v2_frames(&mut unsynchronised, is_track_rva2, None).is_err());
+        let mut old_version = tag(2, 0, &[], 16);
+        assert!(edit_id3v2_frames(&mut old_version, is_track_rva2, None).is_err());
+    }
+
+    #[test]
+    fn decodes_the_txxx_descriptions() {
+        let frames = [
+            frame(3, b"TXXX", b"\x00REPLAYGAIN_TRACK_GAIN\0-6.00 dB"),
+            title(3),
+            frame(3, b"TXXX", b"\x00caf\xE9\0"),
+            frame(3, b"TXXX", b"\x01\xFF\xFEr\0g\0\0\0value"),
+            frame(3, b"TXXX", b"\x02\0r\0G\0\0value"),
+            frame(3, b"TXXX", "\x03replaygain_album_peak\0\u{e9}".as_bytes()),
+        ];
+        let data = [tag(3, 0, &frames, 32), b"audio".to_vec()].concat();
+        assert_eq!(txxx_descriptions(&data), ["REPLAYGAIN_TRACK_GAIN", "caf\u{e9}", "rg", "rG", "replaygain_album_peak"]);
+
+        assert!(txxx_descriptions(&tag(3, 0x80, &frames, 0)).is_empty());
+        assert!(txxx_descriptions(b"audio").is_empty());
+    }
+}
@@ -0,0 +1,364 @@
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use crate::args::ARGS;
+use crate::loudness_types::{Decibel, LinearLoudness};
+use crate::replaygain_scanner::TrackGain;
+use crate::tags::{get_file_extension, is_rg_tag, replace_file_contents, rg_values, track_peak};
+
+pub(crate) const MP4_EXTENSIONS: [&str; 5] = ["mp4", "m4a", "m4b", "m4p", "m4r"];
+
+const ATOM_HEADER_SIZE: usize = 8;
+const ITUNES_MEAN: &[u8] = b"com.apple.iTunes";
+const ITUNNORM: &str = "iTunNORM";
+// the data atom of a UTF-8 text value, followed by an empty locale
+const UTF8_TEXT: [u8; 8] = [0, 0, 0, 1, 0, 0, 0, 0];
+// hdlr of an iTunes metadata meta atom, which cannot be read without it
+const MDIR_HANDLER: [u8; 25] = [0, 0, 0, 0, 0, 0, 0, 0, b'm', b'd', b'i', b'r', b'a', b'p', b'p', b'l', 0, 0, 0, 0, 0, 0, 0, 0, 0];
+const SAMPLE_TABLE_PATH: [&[u8; 4]; 4] = [b"trak", b"mdia", b"minf", b"stbl"];
+
+/// The kind of an atom, where it starts and where it ends.
+type AtomLocation = ([u8; 4], usize, usize);
+
+/// Writes the ReplayGain tags as freeform atoms, which ffmpeg does not write, and the iTunNORM (Sound Check)
+/// value Apple players use instead when asked to.
+pub(crate) fn write_mp4_tags(tags: &TrackGain) -> Result<(), Box<dyn Error>> {
+    let extension = get_file_extension(&tags.filepath);
+    let mut items = rg_values(tags, extension);
+    if ARGS.itunnorm {
+        items.push((ITUNNORM.to_string(), itunnorm(tags.gain, track_peak(tags))));
+    }
+
+    edit_ilst(&tags.filepath, |ilst| {
+        items.iter().fold(ilst.to_vec(), |ilst, (name, value)| set_freeform(&ilst, name, value))
+    })
+}
+
+pub(crate) fn remove_mp4_tags(filepath: &Path) -> Result<(), Box<dyn Error>> {
+    edit_ilst(filepath, |ilst| remove_freeform(ilst, |name| is_rg_tag(name) || (ARGS.itunnorm && name.eq_ignore_ascii_case(ITUNNORM))))
+}
+
+/// Removes the freeform atoms whose name passes `remove`, leaving a file without any as it is.
+pub(crate) fn remove_freeform_items(filepath: &Path, remove: impl Fn(&str) -> bool) -> Result<(), Box<dyn Error>> {
+    if !freeform_names(&fs::read(filepath)?).iter().any(|name| remove(name)) {
+        return Ok(());
+    }
+    edit_ilst(filepath, |ilst| remove_freeform(ilst, remove))
+}
+
+/// The names of the freeform atoms as stored in the file.
+pub(crate) fn freeform_names(data: &[u8]) -> Vec<String> {
+    let ilst = path_body(data, &[b"moov", b"udta", b"meta", b"ilst"]).unwrap_or_default();
+    children(ilst).unwrap_or_default().into_iter()
+        .filter(|(kind, _, _)| kind == b"----")
+        .filter_map(|(_, start, end)| freeform_name(&ilst[start + ATOM_HEADER_SIZE..end]))
+        .collect()
+}
+
+/// The name and text of the ReplayGain and iTunNORM freeform atoms, which ffmpeg does not write back.
+pub(crate) fn read_mp4_tags(data: &[u8]) -> Vec<(String, String)> {
+    let ilst = path_body(data, &[b"moov", b"udta", b"meta", b"ilst"]).unwrap_or_default();
+    children(ilst).unwrap_or_default().into_iter()
+        .filter(|(kind, _, _)| kind == b"----")
+        .filter_map(|(_, start, end)| {
+            let item = &ilst[start + ATOM_HEADER_SIZE..end];
+            let name = freeform_name(item).filter(|name| is_gain_item(name))?;
+            Some((name, freeform_value(item)?))
+        })
+        .collect()
+}
+
+/// Replaces the ReplayGain and iTunNORM freeform atoms with the ones read by `read_mp4_tags`.
+pub(crate) fn restore_mp4_tags(filepath: &Path, items: &[(String, String)]) -> Result<(), Box<dyn Error>> {
+    // a file that never had any keeps its atoms as they are
+    if items.is_empty() && read_mp4_tags(&fs::read(filepath)?).is_empty() {
+        return Ok(());
+    }
+    edit_ilst(filepath, |ilst| {
+        items.iter().fold(remove_freeform(ilst, is_gain_item), |ilst, (name, value)| set_freeform(&ilst, name, value))
+    })
+}
+
+fn is_gain_item(name: &str) -> bool {
+    is_rg_tag(name) || name.eq_ignore_ascii_case(ITUNNORM)
+}
+
+fn itunnorm(gain: Decibel, peak: LinearLoudness) -> String {
+    // the first pairs are the adjustment of the left and right channel in 1/1000 and 1/2500 W,
+    // the fourth pair the peak sample, the others are statistics players do not use
+    let adjustment = |reference: f64| (reference * 10f64.powf(-gain.as_f64() / 10.0)).round().clamp(0.0, 65534.0) as u32;
+    let peak = (peak.as_f64() * 32768.0).round().clamp(0.0, 32767.0) as u32;
+    let values = [adjustment(1000.0), adjustment(1000.0), adjustment(2500.0), adjustment(2500.0), 0, 0, peak, peak, 0, 0];
+
+    values.iter().map(|value| format!(" {:08X}", value)).collect()
+}
+
+fn edit_ilst(filepath: &Path, f: impl FnOnce(&[u8]) -> Vec<u8>) -> Result<(), Box<dyn Error>> {
+    let data = fs::read(filepath)?;
+    let atoms = children(&data)?;
+    if atoms.iter().any(|(kind, _, _)| kind == b"moof") {
+        return Err("Cannot edit the tags of a fragmented MP4 file".into());
+    }
+    let (_, moov_start, moov_end) = *atoms.iter().find(|(kind, _, _)| kind == b"moov").ok_or("The file does not have a moov atom")?;
+    let moov_body = &data[moov_start + ATOM_HEADER_SIZE..moov_end];
+
+    let mut new_body = replace_child(moov_body, b"udta", |udta| {
+        replace_child(udta.unwrap_or_default(), b"meta", |meta| {
+            // meta is a full atom, its children follow the version and flags
+            let (version, meta_children) = match meta {
+                Some(meta) if meta.len() >= 4 => (meta[..4].to_vec(), meta[4..].to_vec()),
+                _ => (vec![0; 4], atom(b"hdlr", &MDIR_HANDLER)),
+            };
+            let ilst = replace_child(&meta_children, b"ilst", |ilst| f(ilst.unwrap_or_default()));
+            [version, ilst].concat()
+        })
+    });
+
+    // the audio data after the moov atom moves with its new size
+    let delta = new_body.len() as i64 - moov_body.len() as i64;
+    if delta != 0 {
+        shift_chunk_offsets(&mut new_body, moov_start as u64, delta)?;
+    }
+
+    let res = [&data[..moov_start], &atom(b"moov", &new_body), &data[moov_end..]].concat();
+    replace_file_contents(filepath, &res)
+}
+
+/// Replaces the freeform iTunes item called `name` in the ilst body, including the ones spelled in another case.
+fn set_freeform(ilst: &[u8], name: &str, value: &str) -> Vec<u8> {
+    let mut res = remove_freeform(ilst, |item| item.eq_ignore_ascii_case(name));
+    let item = [
+        atom(b"mean", &[&[0; 4], ITUNES_MEAN].concat()),
+        atom(b"name", &[&[0; 4], name.as_bytes()].concat()),
+        atom(b"data", &[&UTF8_TEXT, value.as_bytes()].concat()),
+    ].concat();
+    res.extend(atom(b"----", &item));
+    res
+}
+
+fn remove_freeform(ilst: &[u8], remove: impl Fn(&str) -> bool) -> Vec<u8> {
+    let mut res = Vec::with_capacity(ilst.len());
+    for (kind, start, end) in children(ilst).unwrap_or_default() {
+        if &kind == b"----" && freeform_name(&ilst[start + ATOM_HEADER_SIZE..end]).is_some_and(|name| remove(&name)) {
+            continue;
+        }
+        res.extend(&ilst[start..end]);
+    }
+    res
+}
+
+fn freeform_name(item: &[u8]) -> Option<String> {
+    let (_, start, end) = children(item).ok()?.into_iter().find(|(kind, _, _)| kind == b"name")?;
+    item.get(start + ATOM_HEADER_SIZE + 4..end).map(|name| String::from_utf8_lossy(name).to_string())
+}
+
+fn freeform_value(item: &[u8]) -> Option<String> {
+    let (_, start, end) = children(item).ok()?.into_iter().find(|(kind, _, _)| kind == b"data")?;
+    item.get(start + ATOM_HEADER_SIZE + UTF8_TEXT.len()..end).map(|value| String::from_utf8_lossy(value).to_string())
+}
+
+/// The body of the atom at the end of `path`, skipping the version and flags of meta.
+fn path_body<'a>(body: &'a [u8], path: &[&[u8; 4]]) -> Option<&'a [u8]> {
+    let (kind, rest) = match path.split_first() {
+        Some(step) => step,
+        None => return Some(body),
+    };
+    let (_, start, end) = children(body).ok()?.into_iter().find(|(child, _, _)| &child == kind)?;
+    let child = &body[start + ATOM_HEADER_SIZE..end];
+    path_body(if kind == &b"meta" { child.get(4..)? } else { child }, rest)
+}
+
+/// Copies the atoms of a container body, replacing the first `kind` atom with the body `f` builds from it,
+/// or appending one if there is none.
+fn replace_child(body: &[u8], kind: &[u8; 4], f: impl FnOnce(Option<&[u8]>) -> Vec<u8>) -> Vec<u8> {
+    let mut f = Some(f);
+    let mut res = Vec::with_capacity(body.len());
+    for (child, start, end) in children(body).unwrap_or_default() {
+        match (&child == kind, f.take()) {
+            (true, Some(f)) => res.extend(atom(kind, &f(Some(&body[start + ATOM_HEADER_SIZE..end])))),
+            (_, unused) => {
+                f = unused;
+                res.extend(&body[start..end]);
+            }
+        }
+    }
+    if let Some(f) = f {
+        res.extend(atom(kind, &f(None)));
+    }
+    res
+}
+
+/// Adds `delta` to every stco and co64 chunk offset that points past `threshold`.
+fn shift_chunk_offsets(body: &mut [u8], threshold: u64, delta: i64) -> Result<(), Box<dyn Error>> {
+    for (kind, start, end) in children(body)? {
+        let child = &mut body[start + ATOM_HEADER_SIZE..end];
+        if SAMPLE_TABLE_PATH.contains(&&kind) {
+            shift_chunk_offsets(child, threshold, delta)?;
+        } else if &kind == b"stco" || &kind == b"co64" {
+            let width = if &kind == b"stco" { 4 } else { 8 };
+            // version and flags, then the entry count
+            for entry in child.get_mut(8..).unwrap_or_default().chunks_exact_mut(width) {
+                let offset = if width == 4 { u32::from_be_bytes(entry.try_into()?) as u64 } else { u64::from_be_bytes(entry.try_into()?) };
+                if offset <= threshold {
+                    continue;
+                }
+                let shifted = (offset as i64 + delta) as u64;
+                if width == 4 {
+                    entry.copy_from_slice(&u32::try_from(shifted).map_err(|_| "A chunk offset no longer fits into stco")?.to_be_bytes());
+                } else {
+                    entry.copy_from_slice(&shifted.to_be_bytes());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn children(body: &[u8]) -> Result<Vec<AtomLocation>, Box<dyn Error>> {
+    let mut res = Vec::new();
+    let mut pos = 0;
+    while pos + ATOM_HEADER_SIZE <= body.len() {
+        let size = u32::from_be_bytes(body[pos..pos + 4].try_into()?) as usize;
+        let kind: [u8; 4] = body[pos + 4..pos + 8].try_into()?;
+        let end = match size {
+            // the atom extends to the end of the file
+            0 => body.len(),
+            // a 64 bit size follows the kind
+            1 => pos + u64::from_be_bytes(body.get(pos + 8..pos + 16).ok_or("Truncated atom")?.try_into()?) as usize,
+            _ => pos + size,
+        };
+        if end > body.len() || end < pos + ATOM_HEADER_SIZE {
+            return Err(format!("Invalid size of the {} atom", String::from_utf8_lossy(&kind)).into());
+        }
+        res.push((kind, pos, end));
+        pos = end;
+    }
+    Ok(res)
+}
+
+fn atom(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+    [&((body.len() + ATOM_HEADER_SIZE) as u32).to_be_bytes(), kind.as_slice(), body].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tempfile::NamedTempFile;
+
+    use super::*;
+
+    fn chunk_offsets(kind: &[u8; 4], offsets: &[u64]) -> Vec<u8> {
+        let mut body = vec![0; 4];
+        body.extend((offsets.len() as u32).to_be_bytes());
+        for offset in offsets {
+            if kind == b"stco" {
+                body.extend((*offset as u32).to_be_bytes());
+            } else {
+                body.extend(offset.to_be_bytes());
+            }
+        }
+        atom(kind, &body)
+    }
+
+    fn trak(chunk_offsets: Vec<u8>) -> Vec<u8> {
+        let stbl = atom(b"stbl", &[atom(b"stsd", &[0; 8]), chunk_offsets].concat());
+        atom(b"trak", &atom(b"mdia", &atom(b"minf", &stbl)))
+    }
+
+    fn freeform(name: &str, value: &str) -> Vec<u8> {
+        set_freeform(&[], name, value)
+    }
+
+    fn udta(items: &[Vec<u8>]) -> Vec<u8> {
+        let meta = [vec![0; 4], atom(b"hdlr", &MDIR_HANDLER), atom(b"ilst", &items.concat())].concat();
+        atom(b"udta", &atom(b"meta", &meta))
+    }
+
+    /// An MP4 file with one chunk before and one after the moov atom, whose offsets point at "before" and "after".
+    fn mp4(udta: Vec<u8>) -> Vec<u8> {
+        let ftyp = atom(b"ftyp", b"M4A \0\0\0\0");
+        let before = atom(b"mdat", b"before");
+        // the offsets have the same size whatever their value, so a placeholder gives the moov length
+        let moov = |offsets: [u64; 2]| atom(b"moov", &[trak(chunk_offsets(b"stco", &offsets)), trak(chunk_offsets(b"co64", &offsets)), udta.clone()].concat());
+        let before_offset = (ftyp.len() + ATOM_HEADER_SIZE) as u64;
+        let after_offset = (ftyp.len() + before.len() + moov([0, 0]).len() + ATOM_HEADER_SIZE) as u64;
+        [ftyp, before, moov([before_offset, after_offset]), atom(b"mdat", b"after")].concat()
+    }
+
+    fn temp_file(data: &[u8]) -> NamedTempFile {
+        let mut file = tempfile::Builder::new().suffix(".m4a").tempfile().expect("To create a temp file");
+        file.write_all(data).expect("To write the temp file");
+        file
+    }
+
+    /// What the stco and co64 offsets of the file point at.
+    fn chunks(data: &[u8]) -> Vec<String> {
+        let moov = path_body(data, &[b"moov"]).expect("To have a moov atom");
+        let mut res = Vec::new();
+        for (_, start, end) in children(moov).expect("To be atoms").into_iter().filter(|(kind, _, _)| kind == b"trak") {
+            let stbl = path_body(&moov[start + ATOM_HEADER_SIZE..end], &[b"mdia", b"minf", b"stbl"]).expect("To have a sample table");
+            let (kind, start, end) = children(stbl).expect("To be atoms")[1];
+            let width = if &kind == b"stco" { 4 } else { 8 };
+            for entry in stbl[start + ATOM_HEADER_SIZE + 8..end].chunks_exact(width) {
+                let offset = entry.iter().fold(0, |offset, byte| (offset << 8) | *byte as usize);
+                res.push(String::from_utf8_lossy(&data[offset..offset + 5]).to_string());
+            }
+        }
+        res
+    }
+
+    #[test]
+    fn encodes_itunnorm() {
+        assert_eq!(
+            itunnorm(Decibel::new(-6.0), LinearLoudness::new(0.5)),
+            " 00000F8D 00000F8D 000026E1 000026E1 00000000 00000000 00004000 00004000 00000000 00000000",
+        );
+        assert!(itunnorm(Decibel::new(-100.0), LinearLoudness::new(2.0)).starts_with(" 0000FFFE 0000FFFE 0000FFFE 0000FFFE 00000000 00000000 00007FFF"));
+    }
+
+    #[test]
+    fn shifts_the_chunk_offsets_after_the_moov_atom() {
+        let data = mp4(udta(&[atom(b"\xA9nam", b"title")]));
+        assert_eq!(chunks(&data), ["befor", "after", "befor", "after"]);
+        let file = temp_file(&data);
+
+        edit_ilst(file.path(), |ilst| set_freeform(ilst, "REPLAYGAIN_TRACK_GAIN", "-6.00 dB")).expect("To edit the tags");
+        let data = fs::read(file.path()).expect("To read the file");
+        assert_eq!(chunks(&data), ["befor", "after", "befor", "after"]);
+        assert_eq!(freeform_names(&data), ["REPLAYGAIN_TRACK_GAIN"]);
+
+        edit_ilst(file.path(), |ilst| remove_freeform(ilst, is_rg_tag)).expect("To edit the tags");
+        assert_eq!(fs::read(file.path()).expect("To read the file"), mp4(udta(&[atom(b"\xA9nam", b"title")])));
+    }
+
+    #[test]
+    fn creates_the_metadata_atoms() {
+        let file = temp_file(&mp4(Vec::new()));
+
+        edit_ilst(file.path(), |ilst| set_freeform(ilst, "REPLAYGAIN_TRACK_PEAK", "0.500000")).expect("To edit the tags");
+        let data = fs::read(file.path()).expect("To read the file");
+        assert_eq!(data, mp4(udta(&[freeform("REPLAYGAIN_TRACK_PEAK", "0.500000")])));
+        assert_eq!(chunks(&data), ["befor", "after", "befor", "after"]);
+    }
+
+    #[test]
+    fn replaces_the_freeform_items_in_any_case() {
+        let ilst = [atom(b"\xA9nam", b"title"), freeform("replaygain_track_gain", "-1.00 dB"), freeform("iTunNORM", " 0")].concat();
+        let ilst = set_freeform(&ilst, "REPLAYGAIN_TRACK_GAIN", "-6.00 dB");
+        let names: Vec<String> = children(&ilst).expect("To be atoms").into_iter()
+            .filter_map(|(_, start, end)| freeform_name(&ilst[start + ATOM_HEADER_SIZE..end]))
+            .collect();
+        assert_eq!(names, ["iTunNORM", "REPLAYGAIN_TRACK_GAIN"]);
+        assert!(ilst.ends_with(b"\0\0\0\x01\0\0\0\0-6.00 dB"));
+    }
+
+    #[test]
+    fn rejects_what_it_cannot_edit() {
+        let fragmented = [mp4(Vec::new()), atom(b"moof", &[])].concat();
+        assert!(edit_ilst(temp_file(&fragmented).path(), |ilst| ilst.to_vec()).is_err());
+        assert!(edit_ilst(temp_file(&atom(b"ftyp", b"M4A ")).path(), |ilst| ilst.to_vec()).is_err());
+        assert!(children(&[0, 0, 0, 64, b'm', b'o', b'o', b'v']).is_err());
+    }
+}
@@ -0,0 +1,40 @@
+use std::path::Path;
+
+/// The path as text for reports and messages. Bytes that are not UTF-8, like the Latin-1 names of old CD rips,
+/// are written as `\xNN` and backslashes as `\\`, so that the original name can always be recovered.
+#[cfg(unix)]
+pub fn escape_path(path: &Path) -> String {
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut res = String::new();
+    for chunk in path.as_os_str().as_bytes().utf8_chunks() {
+        res.push_str(&chunk.valid().replace('\\', "\\\\"));
+        for byte in chunk.invalid() {
+            res.push_str(&format!("\\x{:02X}", byte));
+        }
+    }
+    res
+}
+
+// paths on other systems are made of Unicode, apart from unpaired surrogates on Windows
+#[cfg(not(unix))]
+pub fn escape_path(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    use super::*;
+
+    #[test]
+    fn escapes_bytes_that_are_not_utf8() {
+        assert_eq!(escape_path(Path::new("music/Björk/a.flac")), "music/Björk/a.flac");
+        // "Björk" in Latin-1
+        assert_eq!(escape_path(Path::new(OsStr::from_bytes(b"music/Bj\xf6rk/a.flac"))), "music/Bj\\xF6rk/a.flac");
+        // a literal backslash cannot be mistaken for an escaped byte
+        assert_eq!(escape_path(Path::new("a\\xF6.flac")), "a\\\\xF6.flac");
+    }
+}
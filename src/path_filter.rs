@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::{Debug, Formatter};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use regex::Regex;
+use walkdir::DirEntry;
+
+use crate::args::ARGS;
+use crate::path_display::escape_path;
+
+/// A file in a directory listing patterns, one per line, to skip in that directory and below it.
+pub const IGNORE_FILE: &str = ".loudgainignore";
+// metadata folders of NAS systems and operating systems, which do not start with a dot
+const SYSTEM_DIRECTORIES: [&str; 4] = ["@eaDir", "#recycle", "$RECYCLE.BIN", "System Volume Information"];
+
+/// A glob over file names or, when it contains a slash, over paths relative to the directory it applies to.
+/// `*` does not cross directories, `**` does, and a trailing slash only matches directories.
+#[derive(Clone)]
+pub struct PathPattern {
+    source: String,
+    regex: Regex,
+    whole_path: bool,
+    directories_only: bool,
+}
+
+impl PathPattern {
+    pub fn matches(&self, relative_path: &Path, is_dir: bool) -> bool {
+        if self.directories_only && !is_dir {
+            return false;
+        }
+        let subject = if self.whole_path {
+            relative_path.to_string_lossy()
+        } else {
+            relative_path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default()
+        };
+        self.regex.is_match(&subject)
+    }
+}
+
+impl FromStr for PathPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let directories_only = s.ends_with('/');
+        let glob = s.trim_end_matches('/');
+        let whole_path = glob.contains('/');
+        let pattern = regex::escape(glob.trim_start_matches('/'))
+            // like in gitignore, "**/" stands for any number of directories, none included
+            .replace("\\*\\*/", "(?:.*/)?")
+            .replace("\\*\\*", ".*")
+            .replace("\\*", "[^/]*")
+            .replace("\\?", "[^/]");
+        let regex = Regex::new(&format!("^{}$", pattern)).map_err(|err| format!("Cannot parse {} into a path pattern: {}", s, err))?;
+
+        Ok(PathPattern { source: s.to_string(), regex, whole_path, directories_only })
+    }
+}
+
+impl fmt::Display for PathPattern {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl Debug for PathPattern {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+/// Decides which entries of a directory walk are scanned, from `--include`, `--exclude`, `--hidden`
+/// and the ignore files found along the way. The root of the walk is always kept.
+pub struct PathFilter {
+    root: PathBuf,
+    ignore_files: HashMap<PathBuf, Vec<PathPattern>>,
+}
+
+impl PathFilter {
+    pub fn new(root: &Path) -> Self {
+        PathFilter { root: root.to_path_buf(), ignore_files: HashMap::new() }
+    }
+
+    /// Whether to descend into a directory or to look at a file any further.
+    pub fn keep_entry(&mut self, entry: &DirEntry) -> bool {
+        if entry.depth() == 0 {
+            return true;
+        }
+        let path = entry.path();
+        let is_dir = entry.file_type().is_dir();
+        if !ARGS.hidden && is_hidden(path) {
+            return false;
+        }
+        let relative = path.strip_prefix(&self.root).unwrap_or(path);
+        if ARGS.exclude.iter().any(|pattern| pattern.matches(relative, is_dir)) {
+            return false;
+        }
+
+        !path.ancestors().skip(1).take(entry.depth()).any(|directory| {
+            let relative = path.strip_prefix(directory).unwrap_or(path);
+            self.ignore_patterns(directory).iter().any(|pattern| pattern.matches(relative, is_dir))
+        })
+    }
+
+    /// Whether a kept file is one of those asked for with `--include`.
+    pub fn includes(&self, path: &Path) -> bool {
+        let relative = path.strip_prefix(&self.root).unwrap_or(path);
+        ARGS.include.is_empty() || ARGS.include.iter().any(|pattern| pattern.matches(relative, false))
+    }
+
+    fn ignore_patterns(&mut self, directory: &Path) -> &[PathPattern] {
+        self.ignore_files.entry(directory.to_path_buf()).or_insert_with(|| read_ignore_file(&directory.join(IGNORE_FILE)))
+    }
+}
+
+fn is_hidden(path: &Path) -> bool {
+    path.file_name().map(|name| name.to_string_lossy())
+        .is_some_and(|name| name.starts_with('.') || SYSTEM_DIRECTORIES.contains(&name.as_ref()))
+}
+
+fn read_ignore_file(file: &Path) -> Vec<PathPattern> {
+    let contents = match fs::read_to_string(file) {
+        Ok(contents) => contents,
+        Err(_) => return Vec::new(),
+    };
+    contents.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')).filter_map(|line| {
+        line.parse().map_err(|err| if !ARGS.quiet {
+            eprintln!("{}: {}", escape_path(file), err);
+        }).ok()
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, path: &str) -> bool {
+        pattern.parse::<PathPattern>().expect("To be a path pattern").matches(Path::new(path), false)
+    }
+
+    #[test]
+    fn double_star_matches_any_number_of_directories() {
+        assert!(matches("**/demos/*.mp3", "demos/x.mp3"));
+        assert!(matches("**/demos/*.mp3", "a/b/demos/x.mp3"));
+        assert!(matches("a/**/b.flac", "a/b.flac"));
+        assert!(matches("a/**/b.flac", "a/x/y/b.flac"));
+        assert!(!matches("**/demos/*.mp3", "xdemos/x.mp3"));
+        assert!(!matches("**/demos/*.mp3", "demos/sub/x.mp3"));
+    }
+
+    #[test]
+    fn single_star_stays_in_its_directory() {
+        assert!(matches("*.flac", "a/b/x.flac"));
+        assert!(!matches("a/*.flac", "a/b/x.flac"));
+        assert!(matches("a/**", "a/b/x.flac"));
+    }
+}
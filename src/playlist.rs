@@ -0,0 +1,156 @@
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::tags::get_file_extension;
+
+pub const PLAYLIST_EXTENSIONS: [&str; 4] = ["m3u", "m3u8", "pls", "cue"];
+const FILE_URL_PREFIX: &str = "file://";
+
+pub fn is_playlist(path: &Path) -> bool {
+    PLAYLIST_EXTENSIONS.contains(&get_file_extension(path).to_lowercase().as_str())
+}
+
+/// The files a playlist lists, with relative paths resolved against the directory of the playlist.
+/// Streams and other URLs are skipped, as there is no file to tag.
+pub fn read_playlist(playlist: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let contents = fs::read(playlist)?;
+    let lines = contents.split(|byte| *byte == b'\n')
+        .map(|line| line.strip_prefix(b"\xEF\xBB\xBF".as_slice()).unwrap_or(line).trim_ascii())
+        .filter(|line| !line.is_empty());
+
+    let entries: Vec<&[u8]> = match get_file_extension(playlist).to_lowercase().as_str() {
+        "pls" => lines.filter_map(pls_entry).collect(),
+        "cue" => lines.filter_map(cue_entry).collect(),
+        _ => lines.filter(|line| !line.starts_with(b"#")).collect(),
+    };
+    let directory = playlist.parent().unwrap_or_else(|| Path::new(""));
+
+    Ok(entries.into_iter().filter_map(entry_path).map(|entry| directory.join(entry)).collect())
+}
+
+/// The files of the scanned list that each playlist of the arguments refers to, directly or through a directory.
+pub fn playlist_albums(arguments: &[PathBuf], files: &[PathBuf]) -> Vec<Vec<PathBuf>> {
+    arguments.iter().filter(|argument| is_playlist(argument)).filter_map(|playlist| {
+        let entries: Vec<PathBuf> = read_playlist(playlist).ok()?.into_iter().filter_map(|entry| fs::canonicalize(entry).ok()).collect();
+        Some(files.iter().filter(|file| entries.iter().any(|entry| file.starts_with(entry))).cloned().collect())
+    }).collect()
+}
+
+// PLS lists its files as File1=..., File2=... next to their titles and lengths
+fn pls_entry(line: &[u8]) -> Option<&[u8]> {
+    let (key, value) = line.split_at(line.iter().position(|byte| *byte == b'=')?);
+    (key.len() > 4 && key[..4].eq_ignore_ascii_case(b"file")).then(|| &value[1..])
+}
+
+// FILE "name.flac" WAVE, with the quotes left out by some rippers when the name has no spaces
+pub(crate) fn cue_entry(line: &[u8]) -> Option<&[u8]> {
+    let rest = line.strip_prefix(b"FILE ".as_slice())?.trim_ascii();
+    match rest.strip_prefix(b"\"".as_slice()) {
+        Some(quoted) => quoted.iter().position(|byte| *byte == b'"').map(|end| &quoted[..end]),
+        None => rest.split(|byte| *byte == b' ').next(),
+    }
+}
+
+pub(crate) fn entry_path(entry: &[u8]) -> Option<PathBuf> {
+    let entry = match entry.strip_prefix(FILE_URL_PREFIX.as_bytes()) {
+        Some(url) => percent_decode(url),
+        None if entry.windows(3).any(|window| window == b"://") => return None,
+        None => entry.to_vec(),
+    };
+    Some(path_from_bytes(entry))
+}
+
+fn percent_decode(url: &[u8]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(url.len());
+    let mut i = 0;
+    while i < url.len() {
+        let escaped = url.get(i + 1..i + 3).and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match (url[i], escaped) {
+            (b'%', Some(byte)) => {
+                res.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                res.push(byte);
+                i += 1;
+            }
+        }
+    }
+    res
+}
+
+// M3U files are in the encoding of the system that wrote them, so their bytes are kept as they are
+#[cfg(unix)]
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    use std::ffi::OsString;
+    use std::os::unix::ffi::OsStringExt;
+
+    PathBuf::from(OsString::from_vec(bytes))
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(name: &str, contents: &[u8]) -> Vec<PathBuf> {
+        let directory = tempfile::tempdir().expect("To create a temp dir");
+        let playlist = directory.path().join(name);
+        fs::write(&playlist, contents).expect("To write the playlist");
+        read_playlist(&playlist).expect("To read the playlist").into_iter()
+            .map(|entry| entry.strip_prefix(directory.path()).map(Path::to_path_buf).unwrap_or(entry))
+            .collect()
+    }
+
+    #[test]
+    fn reads_m3u() {
+        let contents = b"\xEF\xBB\xBF#EXTM3U\r\n#EXTINF:123,Artist - Title\r\nalbum/01 a.flac\r\n\r\n  /music/b.mp3  \r\nhttp://radio.example/stream\r\nfile:///music/caf%C3%A9%20c.ogg\r\nd%20e.opus";
+        assert_eq!(read("list.m3u8", contents), [
+            PathBuf::from("album/01 a.flac"),
+            PathBuf::from("/music/b.mp3"),
+            PathBuf::from("/music/caf\u{e9} c.ogg"),
+            PathBuf::from("d%20e.opus"),
+        ]);
+    }
+
+    #[test]
+    fn reads_pls() {
+        let contents = b"[playlist]\nNumberOfEntries=3\nFile1=a.flac\nTitle1=A\nfile2=sub/b=c.mp3\nLength2=-1\nFile3=https://radio.example/stream\nVersion=2\n";
+        assert_eq!(read("LIST.PLS", contents), [PathBuf::from("a.flac"), PathBuf::from("sub/b=c.mp3")]);
+    }
+
+    #[test]
+    fn reads_the_files_of_a_cue_sheet() {
+        let contents = b"REM GENRE Rock\r\nFILE \"01 a b.wav\" WAVE\r\n  TRACK 01 AUDIO\r\n    INDEX 01 00:00:00\r\nFILE c.flac WAVE\r\n  TRACK 02 AUDIO\r\n    INDEX 01 00:00:00";
+        assert_eq!(read("album.cue", contents), [PathBuf::from("01 a b.wav"), PathBuf::from("c.flac")]);
+    }
+
+    #[test]
+    fn decodes_file_urls() {
+        assert_eq!(entry_path(b"file:///a%2Fb%zz%4"), Some(PathBuf::from("/a/b%zz%4")));
+        assert_eq!(entry_path(b"smb://server/a.flac"), None);
+        assert_eq!(cue_entry(b"FILE \"unterminated WAVE"), None);
+        assert_eq!(cue_entry(b"TRACK 01 AUDIO"), None);
+    }
+
+    #[test]
+    fn groups_the_scanned_files_by_playlist() {
+        let directory = tempfile::tempdir().expect("To create a temp dir");
+        let root = fs::canonicalize(directory.path()).expect("To find the temp dir");
+        fs::create_dir(root.join("album")).expect("To create the album dir");
+        for file in ["album/a.flac", "album/b.flac", "c.flac"] {
+            fs::write(root.join(file), b"").expect("To write the audio file");
+        }
+        fs::write(root.join("album.m3u"), b"album\n").expect("To write the playlist");
+        fs::write(root.join("single.pls"), b"File1=c.flac\nFile2=missing.flac\n").expect("To write the playlist");
+
+        let files: Vec<PathBuf> = ["album/a.flac", "album/b.flac", "c.flac"].iter().map(|file| root.join(file)).collect();
+        let arguments = [root.join("album.m3u"), root.join("c.flac"), root.join("single.pls")];
+        assert_eq!(playlist_albums(&arguments, &files), [files[..2].to_vec(), files[2..].to_vec()]);
+    }
+}
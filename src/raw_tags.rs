@@ -0,0 +1,222 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::ape_tag::{ApeTag, TRAILER_SIZE, trailing_tags_length};
+use crate::mp3_gain::id3v2_length;
+use crate::mp3_tags::txxx_descriptions;
+use crate::mp4_tags::{freeform_names, MP4_EXTENSIONS};
+use crate::tags::get_file_extension;
+
+const FLAC_VORBIS_COMMENT: u8 = 4;
+const ID3V2_HEADER_SIZE: usize = 10;
+const MP4_ATOM_HEADER_SIZE: usize = 16;
+
+/// The tag keys as they are spelled in the file. ffprobe folds keys that only differ in case into one.
+/// Only the parts of the file that hold the tags are read.
+pub(crate) fn raw_tag_keys(filepath: &Path) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut file = File::open(filepath)?;
+    let ape_keys = |file: &mut File| -> Result<Vec<String>, std::io::Error> {
+        let tail = read_at(file, SeekFrom::End(-(TRAILER_SIZE as i64)), TRAILER_SIZE)?;
+        let length = trailing_tags_length(&tail);
+        let tail = read_at(file, SeekFrom::End(-(length as i64)), length)?;
+        Ok(ApeTag::read(&tail).map(|(tag, _)| tag.keys()).unwrap_or_default())
+    };
+
+    Ok(match get_file_extension(filepath) {
+        "flac" => flac_comment_keys(&mut file)?,
+        "ogg" | "oga" | "opus" => ogg_comment_keys(&mut file),
+        "mp3" => {
+            let header = read_at(&mut file, SeekFrom::Start(0), ID3V2_HEADER_SIZE)?;
+            let id3v2 = read_at(&mut file, SeekFrom::Start(0), id3v2_length(&header))?;
+            [txxx_descriptions(&id3v2), ape_keys(&mut file)?].concat()
+        }
+        "wv" | "ape" | "mpc" => ape_keys(&mut file)?,
+        extension if MP4_EXTENSIONS.contains(&extension) => freeform_names(&mp4_moov(&mut file)?),
+        _ => Vec::new(),
+    })
+}
+
+/// Up to `length` bytes from the position, fewer if the file is shorter.
+fn read_at(file: &mut File, position: SeekFrom, length: usize) -> Result<Vec<u8>, std::io::Error> {
+    // seeking before the start of a short file fails, so such a file is read from its start
+    if file.seek(position).is_err() {
+        file.seek(SeekFrom::Start(0))?;
+    }
+    let mut res = Vec::with_capacity(length);
+    file.take(length as u64).read_to_end(&mut res)?;
+    Ok(res)
+}
+
+/// Groups of keys that are spelled differently, but only in case.
+pub(crate) fn case_variants(keys: &[String]) -> Vec<Vec<String>> {
+    let mut res: Vec<Vec<String>> = Vec::new();
+    for key in keys {
+        match res.iter_mut().find(|group| group[0].eq_ignore_ascii_case(key)) {
+            Some(group) if !group.contains(key) => group.push(key.clone()),
+            Some(_) => (),
+            None => res.push(vec![key.clone()]),
+        }
+    }
+    res.retain(|group| group.len() > 1);
+    res
+}
+
+fn flac_comment_keys(file: &mut File) -> Result<Vec<String>, std::io::Error> {
+    if read_at(file, SeekFrom::Start(0), 4)? != b"fLaC" {
+        return Ok(Vec::new());
+    }
+
+    let mut header = [0; 4];
+    // the metadata blocks come first, the comments are read without the audio after them
+    while file.read_exact(&mut header).is_ok() {
+        let length = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        if header[0] & 0x7F == FLAC_VORBIS_COMMENT {
+            let mut block = Vec::with_capacity(length);
+            file.take(length as u64).read_to_end(&mut block)?;
+            return Ok(vorbis_comment_keys(&block));
+        }
+        // the high bit marks the last metadata block
+        if header[0] & 0x80 != 0 {
+            break;
+        }
+        file.seek(SeekFrom::Current(length as i64))?;
+    }
+    Ok(Vec::new())
+}
+
+fn ogg_comment_keys(file: &mut File) -> Vec<String> {
+    // the comments are the second packet of the stream, which may span several pages
+    let mut packets = vec![Vec::new()];
+    let mut header = [0; 27];
+    while packets.len() < 3 && file.read_exact(&mut header).is_ok() && header.starts_with(b"OggS") {
+        let mut lacing = vec![0; header[26] as usize];
+        if file.read_exact(&mut lacing).is_err() {
+            break;
+        }
+        for length in lacing {
+            let mut segment = Vec::with_capacity(length as usize);
+            if file.take(length as u64).read_to_end(&mut segment).is_err() {
+                break;
+            }
+            packets.last_mut().expect("To be a packet").extend(segment);
+            // a segment shorter than 255 bytes ends the packet
+            if length < 255 {
+                packets.push(Vec::new());
+            }
+        }
+    }
+
+    let comments = match packets.get(1) {
+        Some(packet) => packet,
+        None => return Vec::new(),
+    };
+    match comments.strip_prefix(b"\x03vorbis").or_else(|| comments.strip_prefix(b"OpusTags")) {
+        Some(comments) => vorbis_comment_keys(comments),
+        None => Vec::new(),
+    }
+}
+
+fn vorbis_comment_keys(block: &[u8]) -> Vec<String> {
+    let read_u32 = |pos: usize| block.get(pos..pos + 4).map(|bytes| u32::from_le_bytes(bytes.try_into().expect("To be 4 bytes")) as usize);
+
+    let mut res = Vec::new();
+    let vendor_length = read_u32(0).unwrap_or_default();
+    let count = read_u32(4 + vendor_length).unwrap_or_default();
+    let mut pos = 8 + vendor_length;
+    for _ in 0..count {
+        let comment = match read_u32(pos).and_then(|length| block.get(pos + 4..pos + 4 + length)) {
+            Some(comment) => comment,
+            None => break,
+        };
+        let key = comment.split(|byte| *byte == b'=').next().unwrap_or_default();
+        res.push(String::from_utf8_lossy(key).to_string());
+        pos += 4 + comment.len();
+    }
+    res
+}
+
+/// The moov atom, found by skipping over the other top level atoms such as the audio data.
+fn mp4_moov(file: &mut File) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut pos = 0;
+    loop {
+        let header = read_at(file, SeekFrom::Start(pos), MP4_ATOM_HEADER_SIZE)?;
+        if header.len() < 8 {
+            return Ok(Vec::new());
+        }
+        let size = match u32::from_be_bytes(header[0..4].try_into()?) {
+            // the atom extends to the end of the file
+            0 => file.metadata()?.len() - pos,
+            // a 64 bit size follows the kind
+            1 => u64::from_be_bytes(header.get(8..16).ok_or("Truncated atom")?.try_into()?),
+            size => size as u64,
+        };
+        if &header[4..8] == b"moov" {
+            return Ok(read_at(file, SeekFrom::Start(pos), size as usize)?);
+        }
+        if size < 8 {
+            return Err(format!("Invalid size of the {} atom", String::from_utf8_lossy(&header[4..8])).into());
+        }
+        pos += size;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn vorbis_comments(keys: &[&str]) -> Vec<u8> {
+        let mut res = [&3u32.to_le_bytes()[..], b"lib", &(keys.len() as u32).to_le_bytes()].concat();
+        for key in keys {
+            let comment = format!("{}=1", key);
+            res.extend((comment.len() as u32).to_le_bytes());
+            res.extend(comment.as_bytes());
+        }
+        res
+    }
+
+    fn keys(extension: &str, contents: &[u8]) -> Vec<String> {
+        let mut file = tempfile::Builder::new().suffix(&format!(".{}", extension)).tempfile().expect("To create a temp file");
+        file.write_all(contents).expect("To write the temp file");
+        raw_tag_keys(file.path()).expect("To read the tag keys")
+    }
+
+    #[test]
+    fn groups_keys_differing_in_case() {
+        let keys: Vec<String> = ["REPLAYGAIN_TRACK_GAIN", "ARTIST", "replaygain_track_gain", "Replaygain_Track_Gain", "ARTIST"]
+            .iter().map(|key| key.to_string()).collect();
+        assert_eq!(case_variants(&keys), [["REPLAYGAIN_TRACK_GAIN", "replaygain_track_gain", "Replaygain_Track_Gain"]]);
+        assert!(case_variants(&keys[..2]).is_empty());
+    }
+
+    #[test]
+    fn reads_the_flac_comment_keys_as_spelled() {
+        let comments = vorbis_comments(&["REPLAYGAIN_TRACK_GAIN", "replaygain_track_gain"]);
+        // a STREAMINFO block, then the comments as the last block, then the audio
+        let mut flac = [&b"fLaC\x00\x00\x00\x22"[..], &[0; 0x22]].concat();
+        flac.push(0x80 | FLAC_VORBIS_COMMENT);
+        flac.extend(&(comments.len() as u32).to_be_bytes()[1..]);
+        flac.extend(&comments);
+        flac.extend([0xff; 16]);
+
+        assert_eq!(keys("flac", &flac), ["REPLAYGAIN_TRACK_GAIN", "replaygain_track_gain"]);
+        assert!(keys("flac", b"ID3").is_empty());
+    }
+
+    #[test]
+    fn reads_the_opus_comment_keys_as_spelled() {
+        let page = |packets: &[&[u8]]| {
+            let mut res = [&b"OggS"[..], &[0; 22], &[packets.len() as u8]].concat();
+            res.extend(packets.iter().map(|packet| packet.len() as u8));
+            res.extend(packets.concat());
+            res
+        };
+        let comments = [&b"OpusTags"[..], &vorbis_comments(&["R128_TRACK_GAIN", "r128_track_gain"])].concat();
+        let ogg = [page(&[b"OpusHead"]), page(&[&comments])].concat();
+
+        assert_eq!(keys("opus", &ogg), ["R128_TRACK_GAIN", "r128_track_gain"]);
+    }
+}
@@ -1,74 +1,468 @@
 use std::fmt;
 use std::fmt::{Debug, Formatter};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
 
-use ebur128::EbuR128;
+use ebur128::{Channel, EbuR128};
 use ebur128::Error;
+use symphonia::core::audio::Channels;
 
+use crate::args::{ARGS, SampleRateStrategy};
+use crate::channel_layout::{default_layout, downmix_to_stereo, ebur128_channel_map};
 use crate::decode_audio::DecodedFile;
-use crate::gain::calculate_gain;
+use crate::gain::{calculate_gain, reference_loudness};
+use crate::loudness_statistics::{gating_blocks, gating_statistics, GatingStatistics, integrated_loudness};
 use crate::loudness_types::{Decibel, LinearLoudness, LoudnessUnitFullScale};
+use crate::resample::resample;
+
+pub(crate) const TIMELINE_STEPS_PER_SECOND: usize = 10;
+// the rates ebur128 accepts at all
+const SUPPORTED_RATES: RangeInclusive<u32> = 16..=2_822_400;
+// below this range the K-weighting filter gets close to Nyquist, above it true peak is no longer oversampled
+pub const MEASURABLE_RATES: RangeInclusive<u32> = 32_000..=192_000;
+const RESAMPLE_RATE_LOW: u32 = 48_000;
+const RESAMPLE_RATE_HIGH: u32 = 96_000;
+// the shortest input producing a single 400 ms gating block, anything shorter has no integrated loudness
+const MIN_DURATION: f64 = 0.4;
+
+/// Whether the integrated loudness could be measured. Silent and too short files measure -inf LUFS,
+/// so there is no gain that would bring them to the reference loudness.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ScanStatus {
+    Measured,
+    Silent,
+    TooShort,
+}
+
+impl fmt::Display for ScanStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let res = match self {
+            ScanStatus::Measured => "measured",
+            ScanStatus::Silent => "silent",
+            ScanStatus::TooShort => "too short",
+        };
+        write!(f, "{}", res)
+    }
+}
+
+impl ScanStatus {
+    pub fn is_measured(&self) -> bool {
+        matches!(self, ScanStatus::Measured)
+    }
+}
 
 #[derive(Debug)]
 pub struct ScanResult {
+    pub status: ScanStatus,
     pub true_peak: LinearLoudness,
+    pub sample_peak: LinearLoudness,
+    pub channel_peaks: Vec<ChannelPeak>,
     pub loudness_range: Decibel,
     pub integrated_loudness: LoudnessUnitFullScale,
+    pub max_momentary: LoudnessUnitFullScale,
+    pub max_short_term: LoudnessUnitFullScale,
+    pub timeline: Vec<LoudnessPoint>,
+    pub gating: GatingStatistics,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct ChannelPeak {
+    pub true_peak: LinearLoudness,
+    pub sample_peak: LinearLoudness,
+}
+
+/// The gain for playing a set of files, measured as if they were a single one.
+#[derive(Debug, Copy, Clone)]
+pub struct AlbumGain {
+    pub gain: Decibel,
+    pub true_peak: LinearLoudness,
+    pub sample_peak: LinearLoudness,
+    pub integrated_loudness: LoudnessUnitFullScale,
+}
+
+/// Momentary (400 ms) and short-term (3 s) loudness measured at `time` seconds into the file.
+#[derive(Debug, Copy, Clone)]
+pub struct LoudnessPoint {
+    pub time: f64,
+    pub momentary: LoudnessUnitFullScale,
+    pub short_term: LoudnessUnitFullScale,
 }
 
 #[derive(Debug)]
 pub struct TrackGain {
-    pub filepath: String,
+    pub filepath: PathBuf,
+    pub status: ScanStatus,
     pub gain: Decibel,
     pub true_peak: LinearLoudness,
+    pub sample_peak: LinearLoudness,
+    pub channel_peaks: Option<Vec<ChannelPeak>>,
     pub range: Decibel,
     pub reference_loudness: LoudnessUnitFullScale,
     pub integrated_loudness: LoudnessUnitFullScale,
+    pub max_momentary: LoudnessUnitFullScale,
+    pub max_short_term: LoudnessUnitFullScale,
+    pub gating: GatingStatistics,
+    /// The album the file is tagged as a part of.
+    pub album: Option<AlbumGain>,
 }
 
 impl fmt::Display for ScanResult {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "True peak: {}\nLoudness Range: {}\nIntegrated Loudness: {}", self.true_peak, self.loudness_range, self.integrated_loudness)
+        write!(f, "Status: {}\nTrue peak: {}\nSample peak: {}\nLoudness Range: {}\nIntegrated Loudness: {}\nMax Momentary Loudness: {}\nMax Short-term Loudness: {}",
+               self.status, self.true_peak, self.sample_peak, self.loudness_range, self.integrated_loudness, self.max_momentary, self.max_short_term)?;
+        for (channel, peak) in self.channel_peaks.iter().enumerate() {
+            write!(f, "\nChannel {}: true peak: {}, sample peak: {}", channel, peak.true_peak, peak.sample_peak)?;
+        }
+        Ok(())
+    }
+}
+
+impl TrackGain {
+    /// Shifts the measurements to describe the audio after `gain` was applied to it.
+    pub fn apply_gain(&mut self, gain: Decibel) {
+        self.gain = self.gain - gain;
+        self.true_peak = self.true_peak * gain.as_linear();
+        self.sample_peak = self.sample_peak * gain.as_linear();
+        if let Some(channel_peaks) = &mut self.channel_peaks {
+            for peak in channel_peaks {
+                *peak = ChannelPeak::new(peak.true_peak.as_f64() * gain.as_linear().as_f64(), peak.sample_peak.as_f64() * gain.as_linear().as_f64());
+            }
+        }
+        self.integrated_loudness = self.integrated_loudness + gain.as_LUFS();
+        self.max_momentary = self.max_momentary + gain.as_LUFS();
+        self.max_short_term = self.max_short_term + gain.as_LUFS();
+        if let Some(album) = &mut self.album {
+            album.gain = album.gain - gain;
+            album.true_peak = album.true_peak * gain.as_linear();
+            album.sample_peak = album.sample_peak * gain.as_linear();
+            album.integrated_loudness = album.integrated_loudness + gain.as_LUFS();
+        }
     }
 }
 
 impl ScanResult {
-    pub fn new(integrated_loudness: f64, loudness_range: f64, true_peak: f64) -> Self {
+    pub fn new(integrated_loudness: f64, loudness_range: f64, relative_threshold: f64, channel_peaks: Vec<ChannelPeak>, timeline: Vec<LoudnessPoint>) -> Self {
+        // the file peak is the loudest channel, otherwise clipping in any channel but the first goes unnoticed
+        let true_peak = channel_peaks.iter().map(|peak| peak.true_peak).fold(LinearLoudness::new(0.0), max_peak);
+        let sample_peak = channel_peaks.iter().map(|peak| peak.sample_peak).fold(LinearLoudness::new(0.0), max_peak);
+        let max_momentary = timeline.iter().map(|point| point.momentary).fold(LoudnessUnitFullScale::new(f64::NEG_INFINITY), max_loudness);
+        let max_short_term = timeline.iter().map(|point| point.short_term).fold(LoudnessUnitFullScale::new(f64::NEG_INFINITY), max_loudness);
+
+        let duration = timeline.last().map_or(0.0, |point| point.time);
+        let status = if duration < MIN_DURATION {
+            ScanStatus::TooShort
+        } else if !integrated_loudness.is_finite() {
+            ScanStatus::Silent
+        } else {
+            ScanStatus::Measured
+        };
+
         ScanResult {
-            true_peak: LinearLoudness::new(true_peak),
+            status,
+            true_peak,
+            sample_peak,
+            channel_peaks,
             loudness_range: Decibel::new(loudness_range),
             integrated_loudness: LoudnessUnitFullScale::new(integrated_loudness),
+            max_momentary,
+            max_short_term,
+            gating: gating_statistics(&timeline, relative_threshold),
+            timeline,
+        }
+    }
+}
+
+impl ChannelPeak {
+    pub fn new(true_peak: f64, sample_peak: f64) -> Self {
+        ChannelPeak {
+            true_peak: LinearLoudness::new(true_peak),
+            sample_peak: LinearLoudness::new(sample_peak),
+        }
+    }
+}
+
+impl LoudnessPoint {
+    pub fn new(time: f64, momentary: f64, short_term: f64) -> Self {
+        LoudnessPoint {
+            time,
+            momentary: LoudnessUnitFullScale::new(momentary),
+            short_term: LoudnessUnitFullScale::new(short_term),
+        }
+    }
+}
+
+fn max_peak(a: LinearLoudness, b: LinearLoudness) -> LinearLoudness {
+    if b > a { b } else { a }
+}
+
+fn max_loudness(a: LoudnessUnitFullScale, b: LoudnessUnitFullScale) -> LoudnessUnitFullScale {
+    if b > a { b } else { a }
+}
+
+/// Options changing how the loudness is measured, as opposed to how the result is used.
+#[derive(Debug, Default)]
+pub struct ScanOptions {
+    /// Downmix surround files to stereo before measuring, for consistency with stereo releases.
+    pub downmix: bool,
+    /// Count mono files twice, as they sound when played back on both speakers of a stereo system.
+    pub dual_mono: bool,
+    /// What to do with files whose sample rate is outside of `MEASURABLE_RATES`.
+    pub rate_strategy: SampleRateStrategy,
+}
+
+#[derive(Debug)]
+pub enum ScanError {
+    Measurement(Error),
+    UnsupportedSampleRate(u32),
+    UnusualSampleRate(u32),
+}
+
+impl fmt::Display for ScanError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ScanError::Measurement(err) => write!(f, "Loudness measurement failed: {}", err),
+            ScanError::UnsupportedSampleRate(rate) => write!(f, "The sample rate of {} Hz cannot be measured", rate),
+            ScanError::UnusualSampleRate(rate) => write!(f, "The sample rate of {} Hz is outside of {} to {} Hz, use --rate-strategy resample or native to measure it anyway",
+                                                         rate, MEASURABLE_RATES.start(), MEASURABLE_RATES.end()),
         }
     }
 }
 
-pub fn scan_file(file: DecodedFile) -> Result<ScanResult, Error> {
-    let mode = get_mode();
+impl std::error::Error for ScanError {}
 
-    let mut instance = EbuR128::new(file.channels, file.rate, mode)?;
-    instance.add_frames_i16(file.pcm.as_slice())?;
+impl From<Error> for ScanError {
+    fn from(err: Error) -> Self {
+        ScanError::Measurement(err)
+    }
+}
+
+trait Frames: Sized {
+    fn add_to(instance: &mut EbuR128, frames: &[Self]) -> Result<(), Error>;
+}
+
+impl Frames for i16 {
+    fn add_to(instance: &mut EbuR128, frames: &[Self]) -> Result<(), Error> { instance.add_frames_i16(frames) }
+}
+
+impl Frames for f32 {
+    fn add_to(instance: &mut EbuR128, frames: &[Self]) -> Result<(), Error> { instance.add_frames_f32(frames) }
+}
+
+pub fn scan_file(file: DecodedFile, options: &ScanOptions) -> Result<ScanResult, ScanError> {
+    let rate = measurement_rate(file.rate, &options.rate_strategy)?;
+    if rate != file.rate || (options.downmix && file.channels > 2) {
+        return scan_converted(file, rate, options);
+    }
+
+    let mut instance = new_instance(file.channels, file.rate, &file.layout, options)?;
+    let timeline = add_frames(&mut instance, &file.pcm, file.channels, file.rate)?;
+
+    Ok(ScanResult::new(
+        instance.loudness_global()?,
+        instance.loudness_range()?,
+        instance.relative_threshold()?,
+        channel_peaks(&instance, file.channels)?,
+        timeline,
+    ))
+}
+
+/// The sample rate the loudness is measured at, either the rate of the file or the one it is resampled to.
+fn measurement_rate(rate: u32, strategy: &SampleRateStrategy) -> Result<u32, ScanError> {
+    if !SUPPORTED_RATES.contains(&rate) {
+        return Err(ScanError::UnsupportedSampleRate(rate));
+    }
+
+    match strategy {
+        _ if MEASURABLE_RATES.contains(&rate) => Ok(rate),
+        SampleRateStrategy::Native => Ok(rate),
+        SampleRateStrategy::Reject => Err(ScanError::UnusualSampleRate(rate)),
+        SampleRateStrategy::Resample if rate < *MEASURABLE_RATES.start() => Ok(RESAMPLE_RATE_LOW),
+        SampleRateStrategy::Resample => Ok(RESAMPLE_RATE_HIGH),
+    }
+}
+
+fn scan_converted(file: DecodedFile, rate: u32, options: &ScanOptions) -> Result<ScanResult, ScanError> {
+    // the tagged peak has to describe the file as it is played back, not the resampled or downmixed signal
+    let mut peaks = EbuR128::new(file.channels, file.rate, ebur128::Mode::TRUE_PEAK | ebur128::Mode::SAMPLE_PEAK)?;
+    peaks.add_frames_i16(&file.pcm)?;
+
+    let mut pcm = if rate != file.rate {
+        resample(&file.pcm, file.channels, file.rate, rate)
+    } else {
+        file.pcm.iter().map(|sample| *sample as f32 / 32768.0).collect()
+    };
+    let mut channels = file.channels;
+    let mut layout = file.layout;
+    if options.downmix && channels > 2 {
+        pcm = downmix_to_stereo(&pcm, &layout);
+        channels = 2;
+        layout = default_layout(channels);
+    }
+
+    let mut instance = new_instance(channels, rate, &layout, options)?;
+    let timeline = add_frames(&mut instance, &pcm, channels, rate)?;
 
     Ok(ScanResult::new(
         instance.loudness_global()?,
         instance.loudness_range()?,
-        instance.true_peak(0)?,
+        instance.relative_threshold()?,
+        channel_peaks(&peaks, file.channels)?,
+        timeline,
     ))
 }
 
+fn new_instance(channels: u32, rate: u32, layout: &[Channels], options: &ScanOptions) -> Result<EbuR128, Error> {
+    let mut instance = EbuR128::new(channels, rate, get_mode())?;
+    instance.set_channel_map(&ebur128_channel_map(layout))?;
+    if options.dual_mono && channels == 1 {
+        instance.set_channel(0, Channel::DualMono)?;
+    }
+
+    Ok(instance)
+}
+
+fn add_frames<T: Frames>(instance: &mut EbuR128, pcm: &[T], channels: u32, rate: u32) -> Result<Vec<LoudnessPoint>, Error> {
+    // feed the samples in 100 ms steps, the interval at which BS.1770 gating blocks are taken,
+    // so that the momentary and short-term loudness can be sampled along the way
+    let step = ((rate as usize + TIMELINE_STEPS_PER_SECOND / 2) / TIMELINE_STEPS_PER_SECOND).max(1) * channels as usize;
+    let mut timeline = Vec::with_capacity(pcm.len() / step + 1);
+    let mut frames = 0;
+    for chunk in pcm.chunks(step) {
+        T::add_to(instance, chunk)?;
+        frames += chunk.len() / channels as usize;
+        timeline.push(LoudnessPoint::new(
+            frames as f64 / rate as f64,
+            instance.loudness_momentary()?,
+            instance.loudness_shortterm()?,
+        ));
+    }
+
+    Ok(timeline)
+}
+
+fn channel_peaks(instance: &EbuR128, channels: u32) -> Result<Vec<ChannelPeak>, Error> {
+    (0..channels)
+        .map(|channel| Ok(ChannelPeak::new(instance.true_peak(channel)?, instance.sample_peak(channel)?)))
+        .collect()
+}
+
 fn get_mode() -> ebur128::Mode {
     let mut mode = ebur128::Mode::I;
     mode.insert(ebur128::Mode::TRUE_PEAK);
+    mode.insert(ebur128::Mode::SAMPLE_PEAK);
     mode.insert(ebur128::Mode::LRA);
 
     mode
 }
 
-pub fn get_track_gain(filepath: String, scan: ScanResult) -> TrackGain {
+pub fn get_track_gain(filepath: PathBuf, scan: &ScanResult) -> TrackGain {
+    // unmeasurable files are left at unity gain instead of an infinite one
+    let gain = if scan.status.is_measured() { calculate_gain(scan.integrated_loudness, scan.true_peak) } else { Decibel::new(0.0) };
+
     TrackGain {
         filepath,
-        gain: calculate_gain(scan.integrated_loudness, scan.true_peak),
+        status: scan.status,
+        gain,
         true_peak: scan.true_peak,
+        sample_peak: scan.sample_peak,
+        channel_peaks: if ARGS.channel_peaks { Some(scan.channel_peaks.clone()) } else { None },
         range: scan.loudness_range,
-        reference_loudness: LoudnessUnitFullScale::new(-18.0),
+        reference_loudness: reference_loudness(),
         integrated_loudness: scan.integrated_loudness,
+        max_momentary: scan.max_momentary,
+        max_short_term: scan.max_short_term,
+        gating: scan.gating.clone(),
+        album: None,
+    }
+}
+
+pub fn get_album_gain<'a>(scans: impl Iterator<Item=&'a ScanResult> + Clone) -> AlbumGain {
+    let integrated_loudness = integrated_loudness(scans.clone().flat_map(|scan| gating_blocks(&scan.timeline)));
+    let true_peak = scans.clone().map(|scan| scan.true_peak).fold(LinearLoudness::new(0.0), max_peak);
+    let sample_peak = scans.map(|scan| scan.sample_peak).fold(LinearLoudness::new(0.0), max_peak);
+    let gain = if integrated_loudness.as_f64().is_finite() { calculate_gain(integrated_loudness, true_peak) } else { Decibel::new(0.0) };
+
+    AlbumGain {
+        gain,
+        true_peak,
+        sample_peak,
+        integrated_loudness,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+
+    use super::*;
+
+    // a sine at the given level in dBFS, the same in every channel
+    fn sine(level: f64, rate: u32, channels: u32, seconds: f64) -> DecodedFile {
+        let amplitude = 32767.0 * 10f64.powf(level / 20.0);
+        let pcm = (0..(rate as f64 * seconds) as usize)
+            .map(|frame| (amplitude * (2.0 * PI * 1000.0 * frame as f64 / rate as f64).sin()) as i16)
+            .flat_map(|sample| vec![sample; channels as usize])
+            .collect();
+        DecodedFile::new(pcm, channels, rate)
+    }
+
+    #[test]
+    fn samples_the_timeline_every_100_ms() {
+        let scan = scan_file(sine(-20.0, 44_100, 2, 3.0), &ScanOptions::default()).expect("To measure the sine");
+        assert_eq!(scan.timeline.len(), 3 * TIMELINE_STEPS_PER_SECOND);
+        assert!((scan.timeline[9].time - 1.0).abs() < 1e-9);
+        assert!(scan.timeline[0].short_term.as_f64() < scan.timeline[29].short_term.as_f64());
+        assert!((scan.max_momentary.as_f64() - scan.integrated_loudness.as_f64()).abs() < 0.1);
+    }
+
+    #[test]
+    fn measures_unusual_rates_as_asked() {
+        let rate = |rate, strategy| measurement_rate(rate, &strategy).ok();
+        assert_eq!(rate(44_100, SampleRateStrategy::Reject), Some(44_100));
+        assert_eq!(rate(11_025, SampleRateStrategy::Native), Some(11_025));
+        assert_eq!(rate(11_025, SampleRateStrategy::Reject), None);
+        assert_eq!(rate(11_025, SampleRateStrategy::Resample), Some(RESAMPLE_RATE_LOW));
+        assert_eq!(rate(352_800, SampleRateStrategy::Resample), Some(RESAMPLE_RATE_HIGH));
+        assert!(matches!(measurement_rate(8, &SampleRateStrategy::Native), Err(ScanError::UnsupportedSampleRate(8))));
+    }
+
+    #[test]
+    fn takes_the_peak_of_the_loudest_channel() {
+        let mut file = sine(-6.0, 48_000, 2, 1.0);
+        // silence the left channel, clipping on the right only must still show
+        file.pcm.iter_mut().step_by(2).for_each(|sample| *sample = 0);
+        let scan = scan_file(file, &ScanOptions::default()).expect("To measure the sine");
+
+        assert_eq!(scan.channel_peaks[0].sample_peak.as_f64(), 0.0);
+        assert!((scan.sample_peak.as_f64() - 0.5).abs() < 0.01);
+        assert!(scan.true_peak.as_f64() >= scan.sample_peak.as_f64());
+        assert_eq!(scan.sample_peak.as_f64(), scan.channel_peaks[1].sample_peak.as_f64());
+    }
+
+    #[test]
+    fn classifies_silent_and_too_short_files() {
+        let silent = scan_file(sine(f64::NEG_INFINITY, 48_000, 2, 1.0), &ScanOptions::default()).expect("To measure the silence");
+        assert!(matches!(silent.status, ScanStatus::Silent));
+        let short = scan_file(sine(-20.0, 48_000, 2, 0.3), &ScanOptions::default()).expect("To measure the sine");
+        assert!(matches!(short.status, ScanStatus::TooShort));
+        let measured = scan_file(sine(-20.0, 48_000, 2, 1.0), &ScanOptions::default()).expect("To measure the sine");
+        assert!(measured.status.is_measured());
+
+        // unmeasured files are left at unity gain
+        assert_eq!(get_track_gain(PathBuf::from("a.flac"), &silent).gain.as_f64(), 0.0);
+        assert_eq!(get_track_gain(PathBuf::from("a.flac"), &short).gain.as_f64(), 0.0);
+        assert_ne!(get_track_gain(PathBuf::from("a.flac"), &measured).gain.as_f64(), 0.0);
+    }
+
+    #[test]
+    fn measures_mono_as_dual_mono() {
+        let mono = scan_file(sine(-20.0, 48_000, 1, 1.0), &ScanOptions::default()).expect("To measure the sine");
+        let dual_mono = scan_file(sine(-20.0, 48_000, 1, 1.0), &ScanOptions { dual_mono: true, ..Default::default() }).expect("To measure the sine");
+        let stereo = scan_file(sine(-20.0, 48_000, 2, 1.0), &ScanOptions::default()).expect("To measure the sine");
+
+        // as loud as the same signal on both speakers, 3 dB above a single one
+        let difference = dual_mono.integrated_loudness.as_f64() - mono.integrated_loudness.as_f64();
+        assert!((difference - 10.0 * 2f64.log10()).abs() < 0.01, "{}", difference);
+        assert!((dual_mono.integrated_loudness.as_f64() - stereo.integrated_loudness.as_f64()).abs() < 0.01);
     }
 }
@@ -0,0 +1,52 @@
+use std::fmt::Write;
+
+use crate::args::{ARGS, ReportMode};
+use crate::path_display::escape_path;
+use crate::replaygain_scanner::TrackGain;
+
+const HISTOGRAM_WIDTH: usize = 40;
+
+pub fn print_report(track: &TrackGain) {
+    // results are printed from several threads at once, so every report has to go out in a single call
+    match ARGS.report {
+        ReportMode::Debug => println!("{:#?}", track),
+        ReportMode::Human => print!("{}", format_human(track)),
+    }
+}
+
+fn format_human(track: &TrackGain) -> String {
+    let gating = &track.gating;
+    let mut res = String::new();
+
+    writeln!(res, "{}", escape_path(&track.filepath)).unwrap();
+    if !track.status.is_measured() {
+        writeln!(res, "  Status:               {}", track.status).unwrap();
+    }
+    writeln!(res, "  Integrated loudness:  {}", track.integrated_loudness).unwrap();
+    writeln!(res, "  Loudness range:       {} ({} to {})", track.range, gating.loudness_range_low, gating.loudness_range_high).unwrap();
+    writeln!(res, "  Max momentary:        {}", track.max_momentary).unwrap();
+    writeln!(res, "  Max short-term:       {}", track.max_short_term).unwrap();
+    writeln!(res, "  True peak:            {} ({})", track.true_peak, track.true_peak.as_dB()).unwrap();
+    writeln!(res, "  Sample peak:          {} ({})", track.sample_peak, track.sample_peak.as_dB()).unwrap();
+    if let Some(channel_peaks) = &track.channel_peaks {
+        for (channel, peak) in channel_peaks.iter().enumerate() {
+            writeln!(res, "    Channel {}:          true peak {}, sample peak {}", channel, peak.true_peak, peak.sample_peak).unwrap();
+        }
+    }
+    writeln!(res, "  Gain:                 {}", track.gain).unwrap();
+    if let Some(album) = &track.album {
+        writeln!(res, "  Album gain:           {} (album loudness {})", album.gain, album.integrated_loudness).unwrap();
+    }
+    writeln!(res, "  Silence:              {:.1} %", gating.silence * 100.0).unwrap();
+    writeln!(res, "  Below relative gate:  {:.1} % (gate at {})", gating.below_relative_gate * 100.0, gating.relative_threshold).unwrap();
+
+    if let Some(most_blocks) = gating.histogram.iter().map(|bin| bin.blocks).max() {
+        writeln!(res, "  Loudness histogram:").unwrap();
+        for bin in gating.histogram.iter().rev() {
+            let bar = "#".repeat((bin.blocks * HISTOGRAM_WIDTH).div_ceil(most_blocks));
+            writeln!(res, "    {:>12} {:<width$} {}", bin.loudness.to_string(), bar, bin.blocks, width = HISTOGRAM_WIDTH).unwrap();
+        }
+    }
+
+    res
+}
@@ -0,0 +1,106 @@
+use std::f64::consts::PI;
+
+// zero crossings of the sinc on either side of the interpolated sample, plenty for a loudness measurement
+const ZERO_CROSSINGS: f64 = 8.0;
+// the kernel is tabulated at this many fractional positions between two input samples
+const PHASES: usize = 512;
+// keep the transition band below the new Nyquist frequency when downsampling
+const BANDWIDTH: f64 = 0.95;
+
+/// Windowed sinc sample rate conversion of interleaved samples, normalized to [-1.0, 1.0].
+pub fn resample(pcm: &[i16], channels: u32, from: u32, to: u32) -> Vec<f32> {
+    let channels = channels as usize;
+    let frames = pcm.len() / channels;
+    let step = from as f64 / to as f64;
+    let cutoff = (1.0 / step).min(1.0) * BANDWIDTH;
+    let half = (ZERO_CROSSINGS / cutoff).ceil() as usize;
+    let kernel = build_kernel(cutoff, half);
+
+    let output_frames = (frames as u64 * to as u64 / from as u64) as usize;
+    let mut res = Vec::with_capacity(output_frames * channels);
+    for frame in 0..output_frames {
+        let position = frame as f64 * step;
+        let mut base = position.floor() as usize;
+        let mut phase = ((position - base as f64) * PHASES as f64).round() as usize;
+        if phase == PHASES {
+            base += 1;
+            phase = 0;
+        }
+        let weights = &kernel[phase * 2 * half..(phase + 1) * 2 * half];
+
+        // the first input sample under the kernel, which may lie before the start of the file
+        let first = base as isize - half as isize + 1;
+        for channel in 0..channels {
+            let mut sample = 0.0;
+            for (tap, weight) in weights.iter().enumerate() {
+                let input = first + tap as isize;
+                if input >= 0 && (input as usize) < frames {
+                    sample += pcm[input as usize * channels + channel] as f64 * weight;
+                }
+            }
+            res.push((sample / 32768.0) as f32);
+        }
+    }
+
+    res
+}
+
+fn build_kernel(cutoff: f64, half: usize) -> Vec<f64> {
+    let taps = 2 * half;
+    let mut kernel = Vec::with_capacity(PHASES * taps);
+    for phase in 0..PHASES {
+        let fraction = phase as f64 / PHASES as f64;
+        for tap in 0..taps {
+            let distance = (tap as f64 - half as f64 + 1.0) - fraction;
+            kernel.push(cutoff * sinc(cutoff * distance) * blackman(distance / half as f64));
+        }
+    }
+    kernel
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 { 1.0 } else { (PI * x).sin() / (PI * x) }
+}
+
+fn blackman(x: f64) -> f64 {
+    if x.abs() >= 1.0 { 0.0 } else { 0.42 + 0.5 * (PI * x).cos() + 0.08 * (2.0 * PI * x).cos() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // one second of a half scale mono sine
+    fn sine(frequency: f64, rate: u32) -> Vec<i16> {
+        (0..rate).map(|frame| (16384.0 * (2.0 * PI * frequency * frame as f64 / rate as f64).sin()) as i16).collect()
+    }
+
+    fn rms(samples: impl Iterator<Item=f32>) -> f64 {
+        let samples: Vec<f64> = samples.map(|sample| sample as f64).collect();
+        (samples.iter().map(|sample| sample * sample).sum::<f64>() / samples.len() as f64).sqrt()
+    }
+
+    #[test]
+    fn converts_the_length_and_keeps_the_channels_apart() {
+        let pcm: Vec<i16> = sine(1000.0, 11_025).into_iter().flat_map(|sample| [sample, 0]).collect();
+        let resampled = resample(&pcm, 2, 11_025, 48_000);
+        assert_eq!(resampled.len(), 48_000 * 2);
+        assert!(resampled.iter().skip(1).step_by(2).all(|sample| *sample == 0.0));
+    }
+
+    #[test]
+    fn keeps_the_level_of_a_tone_in_the_passband() {
+        // its rms is 0.5 / sqrt(2), the edges are left out of the comparison
+        for (from, to) in [(11_025, 48_000), (352_800, 96_000)] {
+            let resampled = resample(&sine(1000.0, from), 1, from, to);
+            let middle = resampled[to as usize / 10..to as usize * 9 / 10].iter().copied();
+            assert!((rms(middle) - 0.5 / 2f64.sqrt()).abs() < 0.005, "{} to {} Hz", from, to);
+        }
+    }
+
+    #[test]
+    fn removes_what_is_above_the_new_nyquist_frequency() {
+        let resampled = resample(&sine(40_000.0, 352_800), 1, 352_800, 48_000);
+        assert!(rms(resampled[4800..43_200].iter().copied()) < 0.01);
+    }
+}
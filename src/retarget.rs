@@ -0,0 +1,114 @@
+use std::error::Error;
+use std::path::Path;
+
+use crate::args::ARGS;
+use crate::gain::{gain_to, reference_loudness};
+use crate::loudness_statistics::GatingStatistics;
+use crate::loudness_types::{Decibel, LinearLoudness, LoudnessUnitFullScale};
+use crate::path_display::escape_path;
+use crate::replaygain_scanner::{AlbumGain, ScanStatus, TrackGain};
+use crate::stored_tags::{read_stored_tags, StoredTags};
+use crate::tags::remove_album_tags;
+
+/// Derives the loudness of a tagged file from its track gain and reference loudness, without decoding it,
+/// and computes the gain for the current reference and pregain. A gain that was lowered to prevent clipping
+/// makes the file look louder than it is. The album gain is recomputed the same way, or removed if it cannot be,
+/// so that the track and album gains are never relative to different references.
+pub fn retarget(filepath: &Path) -> Result<TrackGain, Box<dyn Error>> {
+    let stored = read_stored_tags(filepath)?;
+    let track = retarget_tags(filepath, &stored, reference_loudness(), ARGS.pregain)?;
+    if stored.has_album() && track.album.is_none() {
+        eprintln!("Removing the album gain of {}, there is no reference loudness to derive its loudness from", escape_path(filepath));
+        remove_album_tags(filepath)?;
+    }
+    Ok(track)
+}
+
+/// The gains of the stored loudness for `reference`, `pregain` being the one the R128 gains were written with.
+fn retarget_tags(filepath: &Path, stored: &StoredTags, reference: LoudnessUnitFullScale, pregain: Decibel) -> Result<TrackGain, Box<dyn Error>> {
+    let integrated_loudness = stored.integrated_loudness(pregain)
+        .ok_or("The file has no track gain and reference loudness to derive its loudness from")?;
+    // without a stored peak there is nothing to prevent clipping against
+    let peak = stored.peak.unwrap_or_else(|| LinearLoudness::new(0.0));
+    let album = stored.album_loudness(pregain).map(|album_loudness| {
+        let album_peak = stored.album_peak.unwrap_or_else(|| LinearLoudness::new(0.0));
+        AlbumGain {
+            gain: gain_to(reference, album_loudness, album_peak),
+            true_peak: album_peak,
+            sample_peak: album_peak,
+            integrated_loudness: album_loudness,
+        }
+    });
+
+    Ok(TrackGain {
+        filepath: filepath.to_path_buf(),
+        status: ScanStatus::Measured,
+        gain: gain_to(reference, integrated_loudness, peak),
+        true_peak: peak,
+        sample_peak: peak,
+        channel_peaks: None,
+        range: stored.range.unwrap_or_else(|| Decibel::new(f64::NAN)),
+        reference_loudness: reference,
+        integrated_loudness,
+        max_momentary: LoudnessUnitFullScale::new(f64::NAN),
+        max_short_term: LoudnessUnitFullScale::new(f64::NAN),
+        gating: GatingStatistics::unknown(),
+        album,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::gain::r128_gain;
+
+    use super::*;
+
+    fn round(value: f64) -> f64 {
+        (value * 1000.0).round() / 1000.0
+    }
+
+    #[test]
+    fn retargets_the_track_and_album_gains() {
+        // tagged for -18 LUFS, the track measured -12 LUFS and the album -11 LUFS
+        let stored = StoredTags {
+            gain: Some(Decibel::new(-6.0)),
+            peak: Some(LinearLoudness::new(0.9)),
+            range: Some(Decibel::new(5.0)),
+            reference_loudness: Some(LoudnessUnitFullScale::new(-18.0)),
+            album_gain: Some(Decibel::new(-7.0)),
+            album_peak: Some(LinearLoudness::new(0.95)),
+            ..Default::default()
+        };
+        let track = retarget_tags(Path::new("a.flac"), &stored, LoudnessUnitFullScale::new(-14.0), Decibel::new(0.0)).expect("To retarget the tags");
+
+        assert_eq!(round(track.gain.as_f64()), -2.0);
+        assert_eq!(round(track.integrated_loudness.as_f64()), -12.0);
+        assert_eq!(track.reference_loudness.as_f64(), -14.0);
+        assert_eq!(track.range.as_f64(), 5.0);
+        let album = track.album.expect("To be the album gain");
+        assert_eq!(round(album.gain.as_f64()), -3.0);
+        assert_eq!(round(album.integrated_loudness.as_f64()), -11.0);
+        assert_eq!(album.true_peak.as_f64(), 0.95);
+    }
+
+    #[test]
+    fn keeps_the_r128_gains_of_the_same_pregain() {
+        let stored = StoredTags { r128_gain: Some(Decibel::new(-3.0)), album_r128_gain: Some(Decibel::new(-4.0)), ..Default::default() };
+        let pregain = Decibel::new(2.0);
+        let track = retarget_tags(Path::new("a.ogg"), &stored, ARGS.reference + pregain.as_LUFS(), pregain).expect("To retarget the tags");
+
+        assert_eq!(round(r128_gain(track.gain).as_f64()), -3.0);
+        assert_eq!(round(r128_gain(track.album.expect("To be the album gain").gain).as_f64()), -4.0);
+    }
+
+    #[test]
+    fn needs_a_reference_loudness() {
+        let stored = StoredTags { gain: Some(Decibel::new(-6.0)), album_gain: Some(Decibel::new(-7.0)), ..Default::default() };
+        assert!(retarget_tags(Path::new("a.flac"), &stored, LoudnessUnitFullScale::new(-14.0), Decibel::new(0.0)).is_err());
+
+        // the track gain is derived from the R128 gain, the album gain has no reference
+        let stored = StoredTags { r128_gain: Some(Decibel::new(-3.0)), album_gain: Some(Decibel::new(-7.0)), ..Default::default() };
+        let track = retarget_tags(Path::new("a.ogg"), &stored, LoudnessUnitFullScale::new(-14.0), Decibel::new(0.0)).expect("To retarget the tags");
+        assert!(stored.has_album() && track.album.is_none());
+    }
+}
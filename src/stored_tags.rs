@@ -0,0 +1,109 @@
+use std::error::Error;
+use std::path::Path;
+
+use crate::ffmpeg::read_tags;
+use crate::gain::R128_REFERENCE;
+use crate::loudness_types::{Decibel, LinearLoudness, LoudnessUnitFullScale};
+use crate::tags::{RG_ALBUM_GAIN, RG_ALBUM_GAIN_OPUS, RG_ALBUM_PEAK, RG_REFERENCE_LOUDNESS, RG_TRACK_GAIN, RG_TRACK_GAIN_OPUS, RG_TRACK_PEAK, RG_TRACK_RANGE};
+
+/// The ReplayGain values a file is already tagged with.
+#[derive(Debug, Default)]
+pub struct StoredTags {
+    pub gain: Option<Decibel>,
+    pub peak: Option<LinearLoudness>,
+    pub range: Option<Decibel>,
+    pub reference_loudness: Option<LoudnessUnitFullScale>,
+    pub r128_gain: Option<Decibel>,
+    pub album_gain: Option<Decibel>,
+    pub album_peak: Option<LinearLoudness>,
+    pub album_r128_gain: Option<Decibel>,
+}
+
+impl StoredTags {
+    pub fn is_empty(&self) -> bool {
+        self.gain.is_none() && self.r128_gain.is_none()
+    }
+
+    pub fn has_album(&self) -> bool {
+        self.album_gain.is_some() || self.album_r128_gain.is_some()
+    }
+
+    /// The loudness the track gain was computed from, which needs the reference it was computed for.
+    /// R128 gains keep no record of the pregain they were written with, it is taken to be `pregain`.
+    pub fn integrated_loudness(&self, pregain: Decibel) -> Option<LoudnessUnitFullScale> {
+        stored_loudness(self.gain, self.reference_loudness, self.r128_gain, pregain)
+    }
+
+    /// The loudness the album gain was computed from, like `integrated_loudness`.
+    pub fn album_loudness(&self, pregain: Decibel) -> Option<LoudnessUnitFullScale> {
+        stored_loudness(self.album_gain, self.reference_loudness, self.album_r128_gain, pregain)
+    }
+}
+
+pub fn read_stored_tags(filepath: &Path) -> Result<StoredTags, Box<dyn Error>> {
+    let tags = read_tags(filepath)?;
+    let tag = |key: &str| tags.iter().find(|(name, _)| name.eq_ignore_ascii_case(key)).and_then(|(_, value)| parse_number(value));
+
+    Ok(StoredTags {
+        gain: tag(RG_TRACK_GAIN).map(Decibel::new),
+        peak: tag(RG_TRACK_PEAK).map(LinearLoudness::new),
+        range: tag(RG_TRACK_RANGE).map(Decibel::new),
+        reference_loudness: tag(RG_REFERENCE_LOUDNESS).map(LoudnessUnitFullScale::new),
+        // a Q7.8 number
+        r128_gain: tag(RG_TRACK_GAIN_OPUS).map(|gain| Decibel::new(gain / 256.0)),
+        album_gain: tag(RG_ALBUM_GAIN).map(Decibel::new),
+        album_peak: tag(RG_ALBUM_PEAK).map(LinearLoudness::new),
+        album_r128_gain: tag(RG_ALBUM_GAIN_OPUS).map(|gain| Decibel::new(gain / 256.0)),
+    })
+}
+
+fn stored_loudness(gain: Option<Decibel>, reference: Option<LoudnessUnitFullScale>, r128_gain: Option<Decibel>, pregain: Decibel) -> Option<LoudnessUnitFullScale> {
+    match (gain, reference, r128_gain) {
+        (Some(gain), Some(reference), _) => Some(reference - gain.as_LUFS()),
+        // the R128 gains are relative to -23 LUFS raised by the pregain, like the other gains to the reference
+        (_, _, Some(gain)) => Some(LoudnessUnitFullScale::new(R128_REFERENCE) + pregain.as_LUFS() - gain.as_LUFS()),
+        _ => None,
+    }
+}
+
+/// The number of a tag value like "-6.52 dB", "-18.00 LUFS" or "0.988312".
+fn parse_number(value: &str) -> Option<f64> {
+    value.split_whitespace().next()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn derives_the_loudness_from_the_reference() {
+        let stored = StoredTags {
+            gain: Some(Decibel::new(-6.0)),
+            reference_loudness: Some(LoudnessUnitFullScale::new(-16.0)),
+            album_gain: Some(Decibel::new(-7.5)),
+            ..Default::default()
+        };
+        assert_eq!(stored.integrated_loudness(Decibel::new(2.0)).map(|loudness| loudness.as_f64()), Some(-10.0));
+        assert_eq!(stored.album_loudness(Decibel::new(2.0)).map(|loudness| loudness.as_f64()), Some(-8.5));
+
+        let stored = StoredTags { reference_loudness: None, ..stored };
+        assert!(stored.integrated_loudness(Decibel::new(0.0)).is_none());
+        assert!(stored.album_loudness(Decibel::new(0.0)).is_none());
+    }
+
+    #[test]
+    fn derives_the_loudness_from_r128_gains_with_the_pregain() {
+        // -18 LUFS brought to -23 LUFS raised by a 2 dB pregain
+        let stored = StoredTags { r128_gain: Some(Decibel::new(-3.0)), album_r128_gain: Some(Decibel::new(-4.0)), ..Default::default() };
+        assert_eq!(stored.integrated_loudness(Decibel::new(2.0)).map(|loudness| loudness.as_f64()), Some(-18.0));
+        assert_eq!(stored.album_loudness(Decibel::new(2.0)).map(|loudness| loudness.as_f64()), Some(-17.0));
+    }
+
+    #[test]
+    fn parses_tag_values() {
+        assert_eq!(parse_number("-6.52 dB"), Some(-6.52));
+        assert_eq!(parse_number("0.988312"), Some(0.988312));
+        assert_eq!(parse_number(" -18.00 LUFS"), Some(-18.0));
+        assert_eq!(parse_number("loud"), None);
+    }
+}
@@ -0,0 +1,236 @@
+use std::error::Error;
+use std::fs;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Map, Value};
+
+use crate::ffmpeg::read_tags;
+use crate::mp3_tags::{read_mp3_tags, restore_mp3_tags, txxx_descriptions};
+use crate::mp4_tags::{MP4_EXTENSIONS, read_mp4_tags, restore_mp4_tags};
+use crate::path_display::escape_path;
+use crate::tags::{ffmpeg_write_tags, get_file_extension, is_rg_tag, rg_tag_removal, swap_files};
+
+const BACKUP_SUFFIX: &str = "rg-backup.json";
+
+/// Saves the ReplayGain tags of the file next to it, unless an older backup already holds its original state.
+pub fn backup_tags(filepath: &Path) -> Result<(), Box<dyn Error>> {
+    let backup = backup_path(filepath);
+    if backup.exists() {
+        return Ok(());
+    }
+
+    let tags = read_tags(filepath)?.into_iter().filter(|(key, _)| is_rg_tag(key)).collect();
+    fs::write(backup, backup_contents(filepath, tags)?.to_string())?;
+    Ok(())
+}
+
+/// Puts back the ReplayGain tags from the backup and deletes it. Returns false if there was nothing to restore.
+pub fn restore_tags(filepath: &Path) -> Result<bool, Box<dyn Error>> {
+    let backup = backup_path(filepath);
+    if !backup.exists() {
+        return Ok(false);
+    }
+
+    let contents: Value = serde_json::from_str(&fs::read_to_string(&backup)?)?;
+    let saved = contents["tags"].as_object().ok_or("The backup does not contain any tags")?;
+    let mut args = rg_tag_removal(get_file_extension(filepath));
+    // clear the tags written since the backup, including the ones the removal above does not know of
+    for (key, _) in read_tags(filepath)?.into_iter().filter(|(key, _)| is_rg_tag(key)) {
+        args.extend(["-metadata".to_string(), format!("{}=", key)]);
+    }
+    for (key, value) in saved {
+        args.extend(["-metadata".to_string(), format!("{}={}", key, value.as_str().unwrap_or_default())]);
+    }
+
+    let new_file = ffmpeg_write_tags(filepath, args)?;
+    swap_files(filepath, new_file.path())?;
+    restore_container_tags(filepath, &contents)?;
+    fs::remove_file(backup)?;
+    Ok(true)
+}
+
+/// The backup of the ReplayGain tags ffprobe reported. ffmpeg writes those as ID3v2 frames or standard atoms, so
+/// the APE items and the track RVA2 frame of MP3 files and the freeform atoms of MP4 files are saved on their own,
+/// to be put back as they were.
+fn backup_contents(filepath: &Path, mut tags: Vec<(String, String)>) -> Result<Value, Box<dyn Error>> {
+    let mut contents = json!({ "file": escape_path(filepath) });
+    let extension = get_file_extension(filepath);
+    if extension == "mp3" {
+        let data = fs::read(filepath)?;
+        let (ape_items, rva2) = read_mp3_tags(&data);
+        // ffprobe merges the APE items into the ID3v2 tags, only those in TXXX frames go back through ffmpeg
+        let descriptions = txxx_descriptions(&data);
+        tags.retain(|(key, _)| descriptions.iter().any(|description| description.eq_ignore_ascii_case(key)));
+        contents["ape"] = items_to_json(ape_items);
+        contents["rva2"] = rva2.map_or(Value::Null, Value::from);
+    } else if MP4_EXTENSIONS.contains(&extension) {
+        let items = read_mp4_tags(&fs::read(filepath)?);
+        tags.retain(|(key, _)| !items.iter().any(|(name, _)| name.eq_ignore_ascii_case(key)));
+        contents["freeform"] = items_to_json(items);
+    }
+
+    // an empty list is kept as well, restoring it removes the tags written since
+    contents["tags"] = items_to_json(tags);
+    Ok(contents)
+}
+
+fn restore_container_tags(filepath: &Path, contents: &Value) -> Result<(), Box<dyn Error>> {
+    if let Some(ape_items) = contents.get("ape") {
+        let rva2 = contents["rva2"].as_array().map(|bytes| bytes.iter().filter_map(Value::as_u64).map(|byte| byte as u8).collect());
+        restore_mp3_tags(filepath, &items_from_json(ape_items), rva2)?;
+    }
+    if let Some(items) = contents.get("freeform") {
+        restore_mp4_tags(filepath, &items_from_json(items))?;
+    }
+    Ok(())
+}
+
+fn items_to_json(items: Vec<(String, String)>) -> Value {
+    Value::Object(items.into_iter().map(|(key, value)| (key, Value::String(value))).collect::<Map<_, _>>())
+}
+
+fn items_from_json(items: &Value) -> Vec<(String, String)> {
+    items.as_object().into_iter().flatten()
+        .map(|(key, value)| (key.clone(), value.as_str().unwrap_or_default().to_string()))
+        .collect()
+}
+
+/// Whether the file is a backup written next to a song.
+pub fn is_backup(path: &Path) -> bool {
+    path.file_name().is_some_and(|name| name.to_string_lossy().ends_with(&format!(".{}", BACKUP_SUFFIX)))
+}
+
+fn backup_path(filepath: &Path) -> PathBuf {
+    let mut backup = OsString::from(filepath);
+    backup.push(format!(".{}", BACKUP_SUFFIX));
+    PathBuf::from(backup)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tempfile::NamedTempFile;
+
+    use crate::ape_tag::{ApeTag, write_ape_tag};
+
+    use super::*;
+
+    const RVA2: &[u8] = b"track\0\x01\xF3\x00\x10\x40\x00";
+
+    fn temp_file(extension: &str, data: &[u8]) -> NamedTempFile {
+        let mut file = tempfile::Builder::new().suffix(extension).tempfile().expect("To create a temp file");
+        file.write_all(data).expect("To write the temp file");
+        file
+    }
+
+    fn tags(items: &[(&str, &str)]) -> Vec<(String, String)> {
+        items.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    fn id3v2_frame(id: &[u8; 4], content: &[u8]) -> Vec<u8> {
+        [id.as_slice(), &(content.len() as u32).to_be_bytes(), &[0, 0], content].concat()
+    }
+
+    /// An MP3 file with the track gain in a TXXX frame and the APE tag and the RVA2 frame when given.
+    fn mp3(rva2: Option<&[u8]>, ape_items: &[(&str, &str)]) -> Vec<u8> {
+        let txxx = id3v2_frame(b"TXXX", b"\x00REPLAYGAIN_TRACK_GAIN\0-6.00 dB");
+        let rva2 = rva2.map(|rva2| id3v2_frame(b"RVA2", rva2)).unwrap_or_default();
+        // the same tag size with and without the RVA2 frame
+        let padding = 64 - rva2.len();
+        let size = (txxx.len() + rva2.len() + padding) as u8;
+        let mut res = [b"ID3\x03\x00\x00\x00\x00\x00".as_slice(), &[size], &txxx, &rva2, &vec![0; padding], b"audio"].concat();
+
+        let mut tag = ApeTag::default();
+        for (key, value) in ape_items {
+            tag.set(key, value);
+        }
+        write_ape_tag(&mut res, &tag);
+        res
+    }
+
+    fn atom(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        [&((body.len() + 8) as u32).to_be_bytes(), kind.as_slice(), body].concat()
+    }
+
+    /// An MP4 file with a title and the given freeform atoms.
+    fn mp4(freeform: &[(&str, &str)]) -> Vec<u8> {
+        let items: Vec<u8> = freeform.iter().flat_map(|(name, value)| atom(b"----", &[
+            atom(b"mean", b"\0\0\0\0com.apple.iTunes"),
+            atom(b"name", &[b"\0\0\0\0", name.as_bytes()].concat()),
+            atom(b"data", &[b"\0\0\0\x01\0\0\0\0", value.as_bytes()].concat()),
+        ].concat())).collect();
+        let ilst = atom(b"ilst", &[atom(b"\xA9nam", b"title"), items].concat());
+        let hdlr = atom(b"hdlr", b"\0\0\0\0\0\0\0\0mdirappl\0\0\0\0\0\0\0\0\0");
+        let moov = atom(b"moov", &atom(b"udta", &atom(b"meta", &[b"\0\0\0\0".as_slice(), &hdlr, &ilst].concat())));
+        [atom(b"ftyp", b"M4A \0\0\0\0"), moov, atom(b"mdat", b"audio")].concat()
+    }
+
+    #[test]
+    fn backs_up_and_restores_the_mp3_tags_ffmpeg_does_not_write() {
+        let ape_items = [("MP3GAIN_UNDO", "+002,+002,N"), ("REPLAYGAIN_ALBUM_GAIN", "-7.00 dB"), ("REPLAYGAIN_TRACK_GAIN", "-6.00 dB")];
+        let original = mp3(Some(RVA2), &ape_items);
+        let file = temp_file(".mp3", &original);
+
+        // ffprobe reports the APE items along with the TXXX frames
+        let contents = backup_contents(file.path(), tags(&[("REPLAYGAIN_TRACK_GAIN", "-6.00 dB"), ("REPLAYGAIN_ALBUM_GAIN", "-7.00 dB")]))
+            .expect("To back up the tags");
+        assert_eq!(contents["tags"], json!({ "REPLAYGAIN_TRACK_GAIN": "-6.00 dB" }));
+        assert_eq!(contents["ape"], json!({ "REPLAYGAIN_ALBUM_GAIN": "-7.00 dB", "REPLAYGAIN_TRACK_GAIN": "-6.00 dB" }));
+        assert_eq!(contents["rva2"], json!(RVA2));
+
+        // a later run wrote other APE values and dropped the RVA2 frame
+        fs::write(file.path(), mp3(None, &[("MP3GAIN_UNDO", "+002,+002,N"), ("REPLAYGAIN_TRACK_GAIN", "-1.00 dB"), ("REPLAYGAIN_TRACK_PEAK", "0.5")]))
+            .expect("To write the temp file");
+        let contents: Value = serde_json::from_str(&contents.to_string()).expect("To be JSON");
+        restore_container_tags(file.path(), &contents).expect("To restore the tags");
+        assert_eq!(fs::read(file.path()).expect("To read the MP3"), original);
+    }
+
+    #[test]
+    fn removes_the_mp3_tags_written_since_the_backup() {
+        let original = mp3(None, &[("MP3GAIN_UNDO", "+002,+002,N")]);
+        let file = temp_file(".mp3", &original);
+        let contents = backup_contents(file.path(), tags(&[("REPLAYGAIN_TRACK_GAIN", "-6.00 dB")])).expect("To back up the tags");
+        assert_eq!(contents["ape"], json!({}));
+        assert_eq!(contents["rva2"], Value::Null);
+
+        fs::write(file.path(), mp3(Some(RVA2), &[("MP3GAIN_UNDO", "+002,+002,N"), ("REPLAYGAIN_TRACK_GAIN", "-1.00 dB")]))
+            .expect("To write the temp file");
+        restore_container_tags(file.path(), &contents).expect("To restore the tags");
+        assert_eq!(fs::read(file.path()).expect("To read the MP3"), original);
+    }
+
+    #[test]
+    fn backs_up_and_restores_the_mp4_freeform_atoms() {
+        let original = mp4(&[("REPLAYGAIN_TRACK_GAIN", "-6.00 dB"), ("iTunNORM", " 00000F8D")]);
+        let file = temp_file(".m4a", &original);
+
+        let contents = backup_contents(file.path(), tags(&[("REPLAYGAIN_TRACK_GAIN", "-6.00 dB")])).expect("To back up the tags");
+        assert_eq!(contents["tags"], json!({}));
+        assert_eq!(contents["freeform"], json!({ "REPLAYGAIN_TRACK_GAIN": "-6.00 dB", "iTunNORM": " 00000F8D" }));
+
+        fs::write(file.path(), mp4(&[("replaygain_track_gain", "-1.00 dB"), ("REPLAYGAIN_TRACK_PEAK", "0.5")])).expect("To write the temp file");
+        restore_container_tags(file.path(), &contents).expect("To restore the tags");
+        assert_eq!(fs::read(file.path()).expect("To read the MP4"), original);
+    }
+
+    #[test]
+    fn leaves_other_files_to_ffmpeg() {
+        let file = temp_file(".flac", b"fLaC");
+        let contents = backup_contents(file.path(), tags(&[("REPLAYGAIN_TRACK_GAIN", "-6.00 dB")])).expect("To back up the tags");
+        assert_eq!(contents["tags"], json!({ "REPLAYGAIN_TRACK_GAIN": "-6.00 dB" }));
+        assert!(contents.get("ape").is_none() && contents.get("freeform").is_none());
+        restore_container_tags(file.path(), &contents).expect("To restore the tags");
+        assert_eq!(fs::read(file.path()).expect("To read the file"), b"fLaC");
+    }
+
+    #[test]
+    fn recognizes_backups() {
+        let backup = backup_path(Path::new("/music/a.flac"));
+        assert_eq!(backup, Path::new("/music/a.flac.rg-backup.json"));
+        assert!(is_backup(&backup));
+        assert!(!is_backup(Path::new("/music/a.json")));
+    }
+}
@@ -0,0 +1,124 @@
+use std::fmt;
+use std::fmt::{Debug, Formatter};
+use std::str::FromStr;
+
+use regex::{Regex, RegexBuilder};
+
+use crate::args::{ARGS, Args, PictureHandling};
+use crate::tags::is_rg_tag;
+
+const REGEX_PREFIX: &str = "re:";
+// Vorbis comments and APE tags keep the cover art in a tag instead of a separate stream
+const PICTURE_TAGS: [&str; 4] = ["METADATA_BLOCK_PICTURE", "COVERART", "COVER ART (FRONT)", "COVER ART (BACK)"];
+
+/// A case insensitive glob (`LYRICS*`) or, prefixed with "re:", a regular expression matching the whole tag key.
+#[derive(Clone)]
+pub struct TagPattern {
+    source: String,
+    regex: Regex,
+}
+
+impl TagPattern {
+    pub fn matches(&self, key: &str) -> bool {
+        self.regex.is_match(key)
+    }
+}
+
+impl FromStr for TagPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let pattern = match s.strip_prefix(REGEX_PREFIX) {
+            Some(regex) => format!("^(?:{})$", regex),
+            None => format!("^{}$", regex::escape(s).replace("\\*", ".*").replace("\\?", ".")),
+        };
+        let regex = RegexBuilder::new(&pattern).case_insensitive(true).build()
+            .map_err(|err| format!("Cannot parse {} into a tag pattern: {}", s, err))?;
+
+        Ok(TagPattern { source: s.to_string(), regex })
+    }
+}
+
+impl fmt::Display for TagPattern {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl Debug for TagPattern {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+/// Whether any of the tag options asks to drop tags or pictures when the file is written.
+pub fn filters_tags() -> bool {
+    ARGS.strip_tags || !ARGS.remove_tags.is_empty() || matches!(ARGS.pictures, PictureHandling::Strip)
+}
+
+/// ReplayGain tags are always kept, then `--keep-tag` wins over `--remove-tag`, and `-S` drops everything else.
+pub fn keep_tag(key: &str) -> bool {
+    keeps_tag(&ARGS, key)
+}
+
+fn keeps_tag(args: &Args, key: &str) -> bool {
+    if is_rg_tag(key) || args.keep_tags.iter().any(|pattern| pattern.matches(key)) {
+        return true;
+    }
+    if matches!(args.pictures, PictureHandling::Strip) && PICTURE_TAGS.iter().any(|picture| picture.eq_ignore_ascii_case(key)) {
+        return false;
+    }
+
+    !args.strip_tags && !args.remove_tags.iter().any(|pattern| pattern.matches(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    fn keeps(options: &[&str], key: &str) -> bool {
+        let args = Args::parse_from([env!("CARGO_PKG_NAME")].iter().chain(options));
+        keeps_tag(&args, key)
+    }
+
+    #[test]
+    fn strips_everything_but_the_replaygain_tags() {
+        assert!(keeps(&[], "ARTIST"));
+        assert!(!keeps(&["-S"], "ARTIST"));
+        assert!(keeps(&["-S"], "REPLAYGAIN_TRACK_GAIN"));
+        assert!(keeps(&["-S"], "r128_track_gain"));
+    }
+
+    #[test]
+    fn parses_globs_and_regexes() {
+        let pattern = |source: &str| source.parse::<TagPattern>().expect("To be a tag pattern");
+        assert!(pattern("LYRICS*").matches("lyrics:eng"));
+        assert!(pattern("disc?").matches("DISCS"));
+        assert!(!pattern("disc?").matches("DISC"));
+        // the other regex characters of a glob are literal
+        assert!(pattern("a.b").matches("A.B") && !pattern("a.b").matches("axb"));
+        assert!(pattern("re:musicbrainz_.*|acoustid_id").matches("ACOUSTID_ID"));
+        assert!(!pattern("re:id").matches("ACOUSTID_ID"));
+        assert!("re:(".parse::<TagPattern>().is_err());
+    }
+
+    #[test]
+    fn keeps_before_it_removes() {
+        assert!(!keeps(&["--remove-tag", "COMMENT*"], "COMMENT"));
+        assert!(keeps(&["--remove-tag", "COMMENT*"], "ARTIST"));
+        assert!(keeps(&["-S", "--keep-tag", "LYRICS*"], "LYRICS"));
+        assert!(keeps(&["--remove-tag", "*", "--keep-tag", "title"], "TITLE"));
+        assert!(keeps(&["--remove-tag", "REPLAYGAIN_*"], "REPLAYGAIN_TRACK_GAIN"));
+    }
+
+    #[test]
+    fn strips_picture_tags() {
+        assert!(keeps(&[], "METADATA_BLOCK_PICTURE"));
+        assert!(!keeps(&["--pictures", "strip"], "metadata_block_picture"));
+        assert!(!keeps(&["--pictures", "strip"], "Cover Art (Front)"));
+        assert!(keeps(&["--pictures", "strip", "--keep-tag", "COVERART"], "COVERART"));
+        assert!(keeps(&["--pictures", "strip"], "ARTIST"));
+    }
+}
@@ -1,19 +1,30 @@
+use std::error::Error;
+use std::ffi::{OsStr, OsString};
 use std::fs;
+use std::io::Write;
 use std::path::Path;
 
-use subprocess::{ExitStatus, Popen, PopenConfig, Redirection};
 use tempfile::{Builder, NamedTempFile};
 
-use crate::args::{ARGS, ScanMode};
-use crate::replaygain_scanner::TrackGain;
+use crate::args::{ARGS, is_stdin, PeakMode, PictureHandling, ScanMode, SilenceHandling, TagCase};
+use crate::ffmpeg::{read_tags, run};
+use crate::gain::r128_gain;
+use crate::loudness_types::LinearLoudness;
+use crate::mp3_tags::{carry_over_trailing_tags, ffmpeg_args, remove_ape_items, remove_mp3_tags, write_mp3_tags};
+use crate::mp4_tags::{MP4_EXTENSIONS, remove_freeform_items, remove_mp4_tags, write_mp4_tags};
+use crate::path_display::escape_path;
+use crate::raw_tags::{case_variants, raw_tag_keys};
+use crate::replaygain_scanner::{AlbumGain, TrackGain};
+use crate::tag_backup::backup_tags;
+use crate::tag_filter::{filters_tags, keep_tag};
 
-const RG_TRACK_GAIN: &str = "REPLAYGAIN_TRACK_GAIN";
-const RG_TRACK_PEAK: &str = "REPLAYGAIN_TRACK_PEAK";
-const RG_ALBUM_GAIN: &str = "REPLAYGAIN_ALBUM_GAIN";
-const RG_ALBUM_PEAK: &str = "REPLAYGAIN_ALBUM_PEAK";
-const RG_TRACK_RANGE: &str = "REPLAYGAIN_TRACK_RANGE";
+pub(crate) const RG_TRACK_GAIN: &str = "REPLAYGAIN_TRACK_GAIN";
+pub(crate) const RG_TRACK_PEAK: &str = "REPLAYGAIN_TRACK_PEAK";
+pub(crate) const RG_ALBUM_GAIN: &str = "REPLAYGAIN_ALBUM_GAIN";
+pub(crate) const RG_ALBUM_PEAK: &str = "REPLAYGAIN_ALBUM_PEAK";
+pub(crate) const RG_TRACK_RANGE: &str = "REPLAYGAIN_TRACK_RANGE";
 const RG_ALBUM_RANGE: &str = "REPLAYGAIN_ALBUM_RANGE";
-const RG_REFERENCE_LOUDNESS: &str = "REPLAYGAIN_REFERENCE_LOUDNESS";
+pub(crate) const RG_REFERENCE_LOUDNESS: &str = "REPLAYGAIN_REFERENCE_LOUDNESS";
 
 const RG_TRACK_GAIN_LOWERCASE: &str = "replaygain_track_gain";
 const RG_TRACK_PEAK_LOWERCASE: &str = "replaygain_track_peak";
@@ -23,39 +34,112 @@ const RG_TRACK_RANGE_LOWERCASE: &str = "replaygain_track_range";
 const RG_ALBUM_RANGE_LOWERCASE: &str = "replaygain_album_range";
 const RG_REFERENCE_LOUDNESS_LOWERCASE: &str = "replaygain_reference_loudness";
 
-const RG_TRACK_GAIN_OPUS: &str = "R128_TRACK_GAIN";
-const RG_ALBUM_GAIN_OPUS: &str = "R128_ALBUM_GAIN";
+pub(crate) const RG_TRACK_GAIN_OPUS: &str = "R128_TRACK_GAIN";
+pub(crate) const RG_ALBUM_GAIN_OPUS: &str = "R128_ALBUM_GAIN";
 
 pub fn save_tags(tags: &TrackGain) -> Result<(), std::io::Error> {
-    match ARGS.scan_mode {
-        ScanMode::DontWriteTags => return Ok(()),
-        ScanMode::DeleteTags => return remove_rg_tags(&tags.filepath),
-        _ => (),
-    };
+    // there is no file to write the tags to
+    if is_stdin(&tags.filepath) {
+        return Ok(());
+    }
 
+    if matches!(ARGS.scan_mode, ScanMode::DontWriteTags) {
+        return Ok(());
+    }
+    if !ARGS.quiet {
+        report_case_variants(&tags.filepath);
+    }
+    if matches!(ARGS.scan_mode, ScanMode::DeleteTags) {
+        backup_tags(&tags.filepath).expect("To be a backup of the ReplayGain tags.");
+        return remove_rg_tags(&tags.filepath);
+    }
+
+    if !tags.status.is_measured() && matches!(ARGS.silence, SilenceHandling::Skip) {
+        if !ARGS.quiet {
+            println!("Not tagging {} as it is {}", escape_path(&tags.filepath), tags.status);
+        }
+        return Ok(());
+    }
+
+    backup_tags(&tags.filepath).expect("To be a backup of the ReplayGain tags.");
     let extension = get_file_extension(&tags.filepath);
-    let formatted = format_tags(tags, extension);
-    let new_file = ffmpeg_write_tags(&tags.filepath, formatted).expect("To be a copy of a song with the replaygain tags written to it.");
-    swap_files(&tags.filepath, new_file.path().to_str().expect("To be a string slice")).unwrap();
-    println!("Hello");
+    let mut args = if filters_tags() { filtered_metadata(&tags.filepath).expect("To be the tags to keep.") } else { Vec::new() };
+    // the new values come last, so they take precedence over the kept ones
+    args.append(&mut format_tags(tags, extension));
+    let new_file = ffmpeg_write_tags(&tags.filepath, args).expect("To be a copy of a song with the replaygain tags written to it.");
+    swap_files(&tags.filepath, new_file.path())?;
 
-    if ARGS.strip_tags {
-        strip_non_rg_metadata(&tags.filepath)?;
+    if extension == "mp3" {
+        write_mp3_tags(tags).expect("To be a song with its APE and RVA2 tags written.");
+    }
+    if MP4_EXTENSIONS.contains(&extension) {
+        write_mp4_tags(tags).expect("To be a song with its freeform ReplayGain atoms written.");
     }
 
     Ok(())
 }
 
-fn strip_non_rg_metadata(filepath: &str) -> Result<(), std::io::Error> {
-    // abuse a little bit the fact that ffmpeg_write_tags takes a vector of strings to pass instead of
-    // tags -map_metadata -1 which tells ffmpeg to remove metadata.
-    // Be sure to call this function BEFORE writing scan results, as this will also remove them
-    let new_file = ffmpeg_write_tags(filepath, vec!["-map_metadata".to_string(), "-1".to_string()]).expect("To work");
-    swap_files(filepath, new_file.path().to_str().expect("To be a string slice."))
+/// Points out keys that only differ in case, which players pick from at random. ffmpeg keeps a single spelling
+/// of every key and the other writers replace every spelling, so writing the tags leaves one of them.
+fn report_case_variants(filepath: &Path) {
+    let keys = raw_tag_keys(filepath).unwrap_or_default();
+    for variants in case_variants(&keys) {
+        println!("{} has tags that only differ in case: {}", escape_path(filepath), variants.join(", "));
+    }
 }
 
-fn remove_rg_tags(filepath: &str) -> Result<(), std::io::Error> {
-    let tags = match get_file_extension(filepath) {
+/// ffmpeg arguments dropping the tags and pictures the tag options ask to remove.
+fn filtered_metadata(filepath: &Path) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut res = vec!["-map_metadata".to_string(), "-1".to_string()];
+    if matches!(ARGS.pictures, PictureHandling::Strip) {
+        // cover art is stored as an attached picture video stream
+        res.extend(["-map".to_string(), "-0:v".to_string()]);
+    }
+    for (key, value) in read_tags(filepath)? {
+        if keep_tag(&key) {
+            res.extend(["-metadata".to_string(), format!("{}={}", key, value)]);
+        }
+    }
+    Ok(res)
+}
+
+pub(crate) fn remove_rg_tags(filepath: &Path) -> Result<(), std::io::Error> {
+    let tags = rg_tag_removal(get_file_extension(filepath));
+    let new_file = ffmpeg_write_tags(filepath, tags).expect("To be a song with ReplayGain tags removed.");
+    swap_files(filepath, new_file.path())?;
+
+    let extension = get_file_extension(filepath);
+    if extension == "mp3" {
+        remove_mp3_tags(filepath).expect("To be a song with its APE and RVA2 ReplayGain tags removed.");
+    }
+    if MP4_EXTENSIONS.contains(&extension) {
+        remove_mp4_tags(filepath).expect("To be a song with its freeform ReplayGain atoms removed.");
+    }
+    Ok(())
+}
+
+/// Removes the album gain and peak of the file, wherever `save_tags` writes them.
+pub(crate) fn remove_album_tags(filepath: &Path) -> Result<(), Box<dyn Error>> {
+    backup_tags(filepath)?;
+    let keys = [
+        RG_ALBUM_GAIN, RG_ALBUM_PEAK, RG_ALBUM_RANGE, RG_ALBUM_GAIN_OPUS,
+        RG_ALBUM_GAIN_LOWERCASE, RG_ALBUM_PEAK_LOWERCASE, RG_ALBUM_RANGE_LOWERCASE,
+    ];
+    let args = keys.iter().flat_map(|key| ["-metadata".to_string(), format!("{}=", key)]).collect();
+    let new_file = ffmpeg_write_tags(filepath, args)?;
+    swap_files(filepath, new_file.path())?;
+
+    let is_album_tag = |key: &str| keys.iter().any(|album| album.eq_ignore_ascii_case(key));
+    match get_file_extension(filepath) {
+        "mp3" => remove_ape_items(filepath, is_album_tag),
+        extension if MP4_EXTENSIONS.contains(&extension) => remove_freeform_items(filepath, is_album_tag),
+        _ => Ok(()),
+    }
+}
+
+/// ffmpeg arguments clearing every ReplayGain tag of a file with the given extension.
+pub(crate) fn rg_tag_removal(extension: &str) -> Vec<String> {
+    match extension {
         // for some reason currently doesn't work for opus. Maybe a ffmpeg bug?
         "ogg" => vec![
             "-metadata".to_string(), format!("{}=", RG_TRACK_GAIN_OPUS),
@@ -77,88 +161,177 @@ fn remove_rg_tags(filepath: &str) -> Result<(), std::io::Error> {
             "-metadata".to_string(), format!("{}=", RG_TRACK_RANGE_LOWERCASE),
             "-metadata".to_string(), format!("{}=", RG_ALBUM_RANGE_LOWERCASE),
         ]
-    };
+    }
+}
 
-    let new_file = ffmpeg_write_tags(filepath, tags).expect("To be a song with ReplayGain tags removed.");
-    swap_files(filepath, new_file.path().to_str().expect("To be a string slice"))
+/// Whether the tag is one of the ReplayGain or R128 gain tags, in any casing.
+pub(crate) fn is_rg_tag(key: &str) -> bool {
+    let key = key.to_uppercase();
+    key.starts_with("REPLAYGAIN_") || key.starts_with("R128_")
 }
 
-fn swap_files(old: &str, new: &str) -> Result<(), std::io::Error> {
+pub(crate) fn swap_files(old: &Path, new: &Path) -> Result<(), std::io::Error> {
     // it's fine to copy, because the temporary file will be deleted when it goes out of scope
     // it might not be deleted if the program terminates abruptly, but it will be in a temp dir anyway
-    let topdir = Path::new(old).parent().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Parent directory does not exists"))?;
-    let name = Path::new(new).file_name().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "File does not have a name"))?;
+    let topdir = old.parent().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Parent directory does not exists"))?;
+    let name = new.file_name().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "File does not have a name"))?;
     let new_path = topdir.join(name);
     fs::copy(new, &new_path)?;
 
     fs::rename(new_path, old)
 }
 
-fn get_file_extension(path: &str) -> &str {
-    let p = Path::new(path);
-    p.extension().expect("To be a file extension").to_str().expect("To be a string slice")
+pub(crate) fn replace_file_contents(filepath: &Path, data: &[u8]) -> Result<(), Box<dyn Error>> {
+    // write next to the original, so that the rename cannot leave a half written file behind
+    let directory = filepath.parent().filter(|directory| !directory.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+    let mut temp_file = NamedTempFile::new_in(directory)?;
+    temp_file.write_all(data)?;
+    temp_file.as_file().set_permissions(fs::metadata(filepath)?.permissions())?;
+    temp_file.persist(filepath)?;
+    Ok(())
+}
+
+/// The extension of the file, or nothing if it has none or one that is not UTF-8, as no supported format does.
+pub(crate) fn get_file_extension(path: &Path) -> &str {
+    path.extension().and_then(OsStr::to_str).unwrap_or_default()
 }
 
-fn ffmpeg_write_tags(filepath: &str, tags: Vec<String>) -> Result<NamedTempFile, Box<dyn std::error::Error>> {
+pub(crate) fn ffmpeg_write_tags(filepath: &Path, tags: Vec<String>) -> Result<NamedTempFile, Box<dyn std::error::Error>> {
     let extension = get_file_extension(filepath);
     let temp_file = Builder::new().prefix("loudgain-").suffix(&format!(".{}", extension)).tempfile()?;
 
-    let popen_args = [
-        vec!["ffmpeg".to_string(),
-             "-hide_banner".to_string(),
-             "-i".to_string(),
-             filepath.to_string(),
-             "-map".to_string(),
-             "0".to_string(),
-             "-y".to_string(),
-             "-codec".to_string(),
-             "copy".to_string()], tags, vec![temp_file.path().to_str().expect("To be a string slice").to_string()]].concat();
-
-    dbg!(popen_args.join(" "));
-
-    let mut p = Popen::create(&popen_args, PopenConfig {
-        stdin: Redirection::Pipe,
-        stdout: Redirection::Pipe,
-        stderr: Redirection::Pipe,
-        detached: false,
-        executable: None,
-        env: None,
-        cwd: None,
-        setuid: None,
-        setgid: None,
-        setpgid: false,
-        _use_default_to_construct: (),
-    })?;
-    let exit_code = p.wait()?;
-
-    match exit_code {
-        ExitStatus::Exited(_) => Ok(temp_file),
-        _ => Err("Incorrect exit status".into()),
+    let mp3_args = if extension == "mp3" { ffmpeg_args() } else { Vec::new() };
+    let popen_args: Vec<OsString> = [
+        vec!["ffmpeg".into(),
+             "-hide_banner".into(),
+             "-i".into(),
+             filepath.into(),
+             "-map".into(),
+             "0".into(),
+             "-y".into(),
+             "-codec".into(),
+             "copy".into()],
+        tags.into_iter().chain(mp3_args).map(OsString::from).collect(),
+        vec![temp_file.path().into()]].concat();
+
+    run(&popen_args)?;
+    if extension == "mp3" {
+        carry_over_trailing_tags(filepath, temp_file.path())?;
     }
+    Ok(temp_file)
 }
 
 fn format_tags(tags: &TrackGain, extension: &str) -> Vec<String> {
+    rg_values(tags, extension).into_iter()
+        .flat_map(|(key, value)| ["-metadata".to_string(), format!("{}={}", key, value)])
+        .collect()
+}
+
+/// The ReplayGain tags to write for the scan mode, as key and value.
+pub(crate) fn rg_values(tags: &TrackGain, extension: &str) -> Vec<(String, String)> {
+    let mut res = track_values(tags, extension);
+    if let Some(album) = &tags.album {
+        res.append(&mut album_values(album, extension));
+    }
+    res
+}
+
+/// The ReplayGain tags of the track alone, for the scan mode.
+pub(crate) fn track_values(tags: &TrackGain, extension: &str) -> Vec<(String, String)> {
     let lufs = matches!(ARGS.scan_mode, ScanMode::WriteExtraTagsLufs);
 
     let mut res = match extension {
         "ogg" => vec![
             // as to replicate the loudgain behavior we don't write track peak tags.
             // also extra tags are not allowed in Opus
-            "-metadata".to_string(), format!("{}={}", RG_TRACK_GAIN_OPUS, tags.gain.to_q78num()),
+            (RG_TRACK_GAIN_OPUS.to_string(), r128_gain(tags.gain).to_q78num().to_string()),
         ],
         _ => vec![
-            "-metadata".to_string(), format!("{}={}", if ARGS.lowercase_tags { RG_TRACK_GAIN_LOWERCASE } else { RG_TRACK_GAIN }, if !lufs { tags.gain.to_string() } else { tags.gain.as_LU().to_string() }),
-            "-metadata".to_string(), format!("{}={}", if ARGS.lowercase_tags { RG_TRACK_PEAK_LOWERCASE } else { RG_TRACK_PEAK }, tags.true_peak),
+            (tag_key(RG_TRACK_GAIN, extension), if !lufs { tags.gain.to_string() } else { tags.gain.as_LU().to_string() }),
+            (tag_key(RG_TRACK_PEAK, extension), track_peak(tags).to_string()),
         ],
     };
 
     if extension != "ogg" && matches!(ARGS.scan_mode, ScanMode::WriteExtraTags) || lufs {
-        res.append(&mut vec![
-            "-metadata".to_string(), format!("{}={}", if ARGS.lowercase_tags { RG_TRACK_RANGE_LOWERCASE } else { RG_TRACK_RANGE }, if !lufs { tags.range.to_string() } else { tags.range.as_LU().to_string() }),
-            // Reference loudness is in LUFS already
-            "-metadata".to_string(), format!("{}={}", if ARGS.lowercase_tags { RG_REFERENCE_LOUDNESS_LOWERCASE } else { RG_REFERENCE_LOUDNESS }, tags.reference_loudness),
-        ]);
+        // a retargeted file without a stored range has none to write
+        if !tags.range.as_f64().is_nan() {
+            res.push((tag_key(RG_TRACK_RANGE, extension), if !lufs { tags.range.to_string() } else { tags.range.as_LU().to_string() }));
+        }
+        // Reference loudness is in LUFS already
+        res.push((tag_key(RG_REFERENCE_LOUDNESS, extension), tags.reference_loudness.to_string()));
     }
 
     res
-}
\ No newline at end of file
+}
+
+fn album_values(album: &AlbumGain, extension: &str) -> Vec<(String, String)> {
+    let lufs = matches!(ARGS.scan_mode, ScanMode::WriteExtraTagsLufs);
+    match extension {
+        "ogg" => vec![(RG_ALBUM_GAIN_OPUS.to_string(), r128_gain(album.gain).to_q78num().to_string())],
+        _ => vec![
+            (tag_key(RG_ALBUM_GAIN, extension), if !lufs { album.gain.to_string() } else { album.gain.as_LU().to_string() }),
+            (tag_key(RG_ALBUM_PEAK, extension), album_peak(album).to_string()),
+        ],
+    }
+}
+
+/// Writes only the album values to a file holding a whole album, whose track values live in a CUE sheet.
+/// The ReplayGain tags it had before are cleared, as track values of the whole file would be misleading.
+pub fn save_album_tags(filepath: &Path, album: &AlbumGain) -> Result<(), Box<dyn Error>> {
+    match ARGS.scan_mode {
+        ScanMode::DontWriteTags => return Ok(()),
+        ScanMode::DeleteTags => {
+            backup_tags(filepath)?;
+            return Ok(remove_rg_tags(filepath)?);
+        }
+        _ => (),
+    };
+
+    backup_tags(filepath)?;
+    let extension = get_file_extension(filepath);
+    let mut args = rg_tag_removal(extension);
+    // the later values win over the removal above
+    for (key, value) in album_values(album, extension) {
+        args.extend(["-metadata".to_string(), format!("{}={}", key, value)]);
+    }
+    let new_file = ffmpeg_write_tags(filepath, args)?;
+    swap_files(filepath, new_file.path())?;
+    Ok(())
+}
+
+/// The ReplayGain key spelled the way the players of the container expect it.
+fn tag_key(key: &str, extension: &str) -> String {
+    let lowercase = match ARGS.tag_case {
+        TagCase::Upper => false,
+        TagCase::Lower => true,
+        // foobar2000 writes lowercase freeform atoms to MP4 files and uppercase keys everywhere else
+        TagCase::Auto => ARGS.lowercase_tags || MP4_EXTENSIONS.contains(&extension),
+    };
+    if lowercase { key.to_lowercase() } else { key.to_string() }
+}
+
+pub(crate) fn track_peak(tags: &TrackGain) -> LinearLoudness {
+    match ARGS.peak_mode {
+        PeakMode::TruePeak => tags.true_peak,
+        PeakMode::SamplePeak => tags.sample_peak,
+    }
+}
+
+fn album_peak(album: &AlbumGain) -> LinearLoudness {
+    match ARGS.peak_mode {
+        PeakMode::TruePeak => album.true_peak,
+        PeakMode::SamplePeak => album.sample_peak,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spells_the_keys_like_foobar2000_by_default() {
+        assert_eq!(tag_key(RG_TRACK_GAIN, "flac"), RG_TRACK_GAIN);
+        assert_eq!(tag_key(RG_TRACK_GAIN, "mp3"), RG_TRACK_GAIN);
+        assert_eq!(tag_key(RG_TRACK_GAIN, "m4a"), RG_TRACK_GAIN_LOWERCASE);
+    }
+}
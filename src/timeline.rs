@@ -0,0 +1,99 @@
+use std::ffi::OsString;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use serde_json::json;
+
+use crate::args::{ARGS, is_stdin, TimelineFormat};
+use crate::path_display::escape_path;
+use crate::replaygain_scanner::ScanResult;
+
+// stdin has no name to derive the timeline name from and no directory to put it into, so use the working directory
+const STDIN_TIMELINE: &str = "./stdin";
+
+pub fn export_timeline(filepath: &Path, scan: &ScanResult, format: &TimelineFormat) -> Result<PathBuf, std::io::Error> {
+    let output = timeline_path(filepath, format)?;
+    let contents = match format {
+        TimelineFormat::Csv => format_csv(scan),
+        TimelineFormat::Json => format_json(filepath, scan),
+    };
+
+    fs::File::create(&output)?.write_all(contents.as_bytes())?;
+    Ok(output)
+}
+
+fn timeline_path(filepath: &Path, format: &TimelineFormat) -> Result<PathBuf, std::io::Error> {
+    let path = if is_stdin(filepath) { Path::new(STDIN_TIMELINE) } else { filepath };
+    let stem = path.file_stem().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "File does not have a name"))?;
+    let directory = match &ARGS.timeline_dir {
+        Some(directory) => directory.clone(),
+        None => path.parent().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Parent directory does not exists"))?.to_path_buf(),
+    };
+
+    let mut name = OsString::from(stem);
+    name.push(format!(".loudness.{}", format));
+    Ok(directory.join(name))
+}
+
+fn format_csv(scan: &ScanResult) -> String {
+    let mut res = String::from("time,momentary,short_term\n");
+    for point in &scan.timeline {
+        res.push_str(&format!("{:.1},{:.2},{:.2}\n", point.time, point.momentary.as_f64(), point.short_term.as_f64()));
+    }
+    res
+}
+
+fn format_json(filepath: &Path, scan: &ScanResult) -> String {
+    // non-finite values (silence is -inf LUFS) have no JSON representation and end up as null
+    let points: Vec<_> = scan.timeline.iter().map(|point| json!({
+        "time": point.time,
+        "momentary": point.momentary.as_f64(),
+        "short_term": point.short_term.as_f64(),
+    })).collect();
+
+    json!({
+        "file": escape_path(filepath),
+        "max_momentary": scan.max_momentary.as_f64(),
+        "max_short_term": scan.max_short_term.as_f64(),
+        "timeline": points,
+    }).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::args::STDIN;
+    use crate::replaygain_scanner::{ChannelPeak, LoudnessPoint};
+
+    use super::*;
+
+    fn scan() -> ScanResult {
+        let timeline = vec![
+            LoudnessPoint::new(0.1, f64::NEG_INFINITY, f64::NEG_INFINITY),
+            LoudnessPoint::new(0.2, -20.126, -21.0),
+            LoudnessPoint::new(0.3, -19.5, -20.25),
+        ];
+        ScanResult::new(-20.0, 0.0, -30.0, vec![ChannelPeak::new(0.5, 0.5)], timeline)
+    }
+
+    #[test]
+    fn names_the_timeline_after_the_file() {
+        assert_eq!(timeline_path(Path::new("music/a.flac"), &TimelineFormat::Csv).expect("To be a path"), Path::new("music/a.loudness.csv"));
+        assert_eq!(timeline_path(Path::new(STDIN), &TimelineFormat::Json).expect("To be a path"), Path::new("./stdin.loudness.json"));
+    }
+
+    #[test]
+    fn formats_csv() {
+        assert_eq!(format_csv(&scan()), "time,momentary,short_term\n0.1,-inf,-inf\n0.2,-20.13,-21.00\n0.3,-19.50,-20.25\n");
+    }
+
+    #[test]
+    fn formats_json_with_silence_as_null() {
+        let json: serde_json::Value = serde_json::from_str(&format_json(Path::new("a.flac"), &scan())).expect("To be JSON");
+        assert_eq!(json["file"], "a.flac");
+        assert_eq!(json["max_momentary"], -19.5);
+        assert_eq!(json["max_short_term"], -20.25);
+        assert_eq!(json["timeline"][0], json!({"time": 0.1, "momentary": null, "short_term": null}));
+        assert_eq!(json["timeline"][2]["momentary"], -19.5);
+    }
+}
@@ -0,0 +1,93 @@
+use std::error::Error;
+use std::fmt;
+use std::fmt::Formatter;
+use std::path::PathBuf;
+
+use crate::args::ARGS;
+use crate::gain::{R128_REFERENCE, r128_gain, reference_loudness};
+use crate::loudness_types::Decibel;
+use crate::path_display::escape_path;
+use crate::replaygain_scanner::TrackGain;
+use crate::stored_tags::read_stored_tags;
+use crate::tags::track_peak;
+
+/// How the ReplayGain tags of a file compare to a new scan of it.
+pub struct Verification {
+    pub filepath: PathBuf,
+    pub problems: Vec<String>,
+}
+
+impl Verification {
+    pub fn matches(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl fmt::Display for Verification {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.matches() {
+            write!(f, "{}: OK", escape_path(&self.filepath))
+        } else {
+            write!(f, "{}: {}", escape_path(&self.filepath), self.problems.join(", "))
+        }
+    }
+}
+
+/// Compares the stored gain and peak to the scan, within `--tolerance`. A stored reference loudness
+/// is taken into account, so files tagged for another reference still match.
+pub fn verify(track: &TrackGain) -> Result<Verification, Box<dyn Error>> {
+    let stored = read_stored_tags(&track.filepath)?;
+    let mut problems = Vec::new();
+    if stored.is_empty() {
+        problems.push("no ReplayGain tags".to_string());
+    }
+    if let Some(gain) = stored.gain {
+        let reference = stored.reference_loudness.unwrap_or_else(reference_loudness);
+        problems.extend(mismatch("gain", gain, track.gain + (reference - reference_loudness()).as_dB()));
+    }
+    if let Some(gain) = stored.r128_gain {
+        problems.extend(r128_mismatch(gain, track.gain));
+    }
+    if let Some(peak) = stored.peak {
+        problems.extend(mismatch("peak", peak.as_dB(), track_peak(track).as_dB()));
+    }
+
+    Ok(Verification { filepath: track.filepath.clone(), problems })
+}
+
+fn mismatch(what: &str, stored: Decibel, measured: Decibel) -> Option<String> {
+    if (stored - measured).as_f64().abs() > ARGS.tolerance.as_f64() {
+        Some(format!("{} stored {}, measured {}", what, stored, measured))
+    } else { None }
+}
+
+/// R128 gains written before they were made relative to -23 LUFS are relative to the ReplayGain reference,
+/// which is said instead of reporting a plain mismatch.
+fn r128_mismatch(stored: Decibel, gain: Decibel) -> Option<String> {
+    let problem = mismatch("R128 gain", stored, r128_gain(gain))?;
+    if mismatch("R128 gain", stored, gain).is_none() {
+        Some(format!("{} (relative to the ReplayGain reference instead of {} LUFS as in older versions, rescan to update it)", problem, R128_REFERENCE))
+    } else { Some(problem) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_r128_gains_within_the_tolerance() {
+        // 2 dB for the default -18 LUFS reference is -3 dB for -23 LUFS
+        assert_eq!(r128_mismatch(Decibel::new(-3.05), Decibel::new(2.0)), None);
+        assert!(r128_mismatch(Decibel::new(-2.5), Decibel::new(2.0)).is_some());
+    }
+
+    #[test]
+    fn explains_r128_gains_of_older_versions() {
+        let problem = r128_mismatch(Decibel::new(2.0), Decibel::new(2.0)).expect("To be a mismatch");
+        assert!(problem.starts_with("R128 gain stored"));
+        assert!(problem.contains("rescan to update it"));
+
+        let problem = r128_mismatch(Decibel::new(0.0), Decibel::new(2.0)).expect("To be a mismatch");
+        assert!(!problem.contains("rescan"));
+    }
+}